@@ -0,0 +1,25 @@
+-- Your SQL goes here
+
+create table refresh_tokens
+(
+    id         varchar   not null
+        constraint refresh_tokens_pk
+            primary key,
+    -- sha256 of the opaque token handed to the client, the token itself is never stored
+    token_hash varchar   not null,
+    user_id    varchar   not null
+        constraint refresh_tokens_users_id_fk
+            references users
+            on delete cascade,
+    -- every token rotated from the same login shares a family
+    family_id  varchar   not null,
+    expires_at timestamp not null,
+    revoked    boolean   not null default false,
+    created_at timestamp not null default (now() at time zone 'utc')
+);
+
+create unique index refresh_tokens_token_hash_uindex
+    on refresh_tokens (token_hash);
+
+create index refresh_tokens_family_id_index
+    on refresh_tokens (family_id);
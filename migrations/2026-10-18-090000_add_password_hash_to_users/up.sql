@@ -0,0 +1,5 @@
+-- Your SQL goes here
+
+-- PHC string ($argon2id$...), null for users that cannot log in with a password
+alter table users
+    add password_hash varchar;
@@ -0,0 +1,28 @@
+-- Your SQL goes here
+
+-- the permissions of each role live in `src/rbac.rs`
+create table roles
+(
+    name        varchar not null
+        constraint roles_pk
+            primary key,
+    description varchar
+);
+
+insert into roles (name, description)
+values ('admin', 'full access, including role management'),
+       ('operator', 'create and update users'),
+       ('viewer', 'read-only access to user data');
+
+create table user_roles
+(
+    user_id varchar not null
+        constraint user_roles_users_id_fk
+            references users
+            on delete cascade,
+    role    varchar not null
+        constraint user_roles_roles_name_fk
+            references roles,
+    constraint user_roles_pk
+        primary key (user_id, role)
+);
@@ -0,0 +1,5 @@
+-- Your SQL goes here
+
+-- serves the `meta @> ...` containment filters of `GET /users`
+create index users_meta_gin_index
+    on users using gin (meta jsonb_path_ops);
@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+
+drop index users_username_trgm_index;
+
+drop extension if exists pg_trgm;
@@ -0,0 +1,7 @@
+-- Your SQL goes here
+
+-- trigram matching for `GET /users/search`, the index serves `ILIKE '%q%'`, `q <% username` and `username % q`
+create extension if not exists pg_trgm;
+
+create index users_username_trgm_index
+    on users using gin (username gin_trgm_ops);
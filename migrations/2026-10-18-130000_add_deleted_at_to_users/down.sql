@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+
+drop index users_deleted_at_index;
+
+alter table users
+    drop column deleted_at;
@@ -0,0 +1,9 @@
+-- Your SQL goes here
+
+-- soft delete: `DELETE /users/{id}` sets it, the purge job removes rows past the retention period
+alter table users
+    add deleted_at timestamp;
+
+create index users_deleted_at_index
+    on users (deleted_at)
+    where deleted_at is not null;
@@ -0,0 +1,10 @@
+-- This file should undo anything in `up.sql`
+
+drop trigger set_updated_at on users;
+
+alter table users
+    drop column updated_at,
+    alter column deleted_at type timestamp,
+    alter column created_at drop not null,
+    alter column created_at drop default,
+    alter column created_at type timestamp;
@@ -0,0 +1,16 @@
+-- Your SQL goes here
+
+-- rows from before `created_at` was set get the migration time
+update users
+set created_at = now()
+where created_at is null;
+
+-- existing timestamps are naive, they are read in the database's time zone
+alter table users
+    alter column created_at type timestamptz,
+    alter column created_at set default now(),
+    alter column created_at set not null,
+    alter column deleted_at type timestamptz,
+    add updated_at timestamptz not null default now();
+
+select diesel_manage_updated_at('users');
@@ -0,0 +1,8 @@
+-- This file should undo anything in `up.sql`
+
+drop trigger bump_version on users;
+
+drop function users_bump_version();
+
+alter table users
+    drop column version;
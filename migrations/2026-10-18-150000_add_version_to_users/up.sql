@@ -0,0 +1,20 @@
+-- Your SQL goes here
+
+-- optimistic concurrency: every change bumps `version`, which clients see as the `ETag`
+alter table users
+    add version integer not null default 1;
+
+create or replace function users_bump_version() returns trigger as $$
+begin
+    if (NEW is distinct from OLD) then
+        NEW.version := OLD.version + 1;
+    end if;
+    return NEW;
+end;
+$$ language plpgsql;
+
+create trigger bump_version
+    before update
+    on users
+    for each row
+execute procedure users_bump_version();
@@ -0,0 +1,19 @@
+-- Your SQL goes here
+
+-- one row per change of a user, kept after the user is purged, so no foreign key
+create table audit_log
+(
+    id         bigserial primary key,
+    user_id    varchar     not null,
+    action     varchar     not null check (action in ('create', 'update', 'delete', 'restore')),
+    -- `sub` of the access token, null for anonymous sign-ups
+    actor_id   varchar,
+    request_id varchar     not null,
+    -- `TypedUser` snapshots, without the password hash
+    before     jsonb,
+    after      jsonb,
+    created_at timestamptz not null default now()
+);
+
+create index audit_log_user_id_created_at_index
+    on audit_log (user_id, created_at desc, id desc);
@@ -0,0 +1,11 @@
+-- This file should undo anything in `up.sql`
+
+delete from audit_log
+where action in ('password_change', 'purge');
+
+alter table audit_log
+    drop constraint audit_log_action_check;
+
+alter table audit_log
+    add constraint audit_log_action_check
+        check (action in ('create', 'update', 'delete', 'restore'));
@@ -0,0 +1,9 @@
+-- Your SQL goes here
+
+-- password changes are updates that leave the snapshots alike, purged users have no `after`
+alter table audit_log
+    drop constraint audit_log_action_check;
+
+alter table audit_log
+    add constraint audit_log_action_check
+        check (action in ('create', 'update', 'delete', 'restore', 'password_change', 'purge'));
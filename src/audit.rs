@@ -0,0 +1,152 @@
+use axum::extract::{FromRef, FromRequestParts};
+use axum::http::request::Parts;
+use axum::Json;
+use chrono::{DateTime, Utc};
+use diesel::{insert_into, ExpressionMethods, PgConnection, QueryDsl, Queryable, RunQueryDsl};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::debug;
+use crate::auth::{AuthUser, MyJwtState};
+use crate::pagination::{Page, PageParams};
+use crate::schema::audit_log;
+use crate::{internal_error, uuid, DbConn, HtyErr, HtyErrCode, MyResponse, PathParam};
+
+const REQUEST_ID_HEADER: &str = "x-request-id";
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+// UUIDs, ULIDs and the usual tracing ids fit, anything else is replaced instead of stored in `audit_log`
+fn valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LEN
+        && id.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'))
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditAction {
+    Create,
+    Update,
+    Delete,
+    Restore,
+    PasswordChange,
+    // hard delete past the retention period, see `purge`
+    Purge,
+}
+
+impl AuditAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditAction::Create => "create",
+            AuditAction::Update => "update",
+            AuditAction::Delete => "delete",
+            AuditAction::Restore => "restore",
+            AuditAction::PasswordChange => "password_change",
+            AuditAction::Purge => "purge",
+        }
+    }
+}
+
+// Who made a change, passed down to every `db_*` function that writes `users`.
+#[derive(Debug, Clone, Default)]
+pub struct Actor {
+    // `sub` of the access token, `None` on anonymous routes such as `/register`
+    pub user_id: Option<String>,
+    // the caller's `X-Request-Id`, generated when absent or invalid, see `valid_request_id`
+    pub request_id: String,
+}
+
+impl<S> FromRequestParts<S> for Actor
+    where
+        MyJwtState: FromRef<S>,
+        S: Send + Sync, {
+    type Rejection = HtyErr;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        // routes that need a caller are guarded by `rbac::authorize` already
+        let user_id = AuthUser::from_request_parts(parts, state).await.ok().map(|auth_user| auth_user.sub.clone());
+        let given = parts.headers.get(REQUEST_ID_HEADER).map(|v| v.to_str().ok().filter(|id| valid_request_id(id)));
+        let request_id = match given {
+            Some(Some(id)) => id.to_string(),
+            Some(None) => {
+                debug!("actor -> invalid {} header, generating one", REQUEST_ID_HEADER);
+                uuid()
+            }
+            None => uuid(),
+        };
+
+        Ok(Actor { user_id, request_id })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Queryable)]
+#[diesel(table_name = audit_log)]
+pub struct AuditEntry {
+    pub id: i64,
+    pub user_id: String,
+    pub action: String,
+    pub actor_id: Option<String>,
+    pub request_id: String,
+    pub before: Option<Value>,
+    pub after: Option<Value>,
+    pub created_at: DateTime<Utc>,
+}
+
+// Records a change of `id_user`, call it inside the transaction that made the change.
+pub fn db_record<T: Serialize>(
+    conn: &mut PgConnection,
+    actor: &Actor,
+    action: AuditAction,
+    id_user: &str,
+    before: Option<&T>,
+    after: Option<&T>) -> Result<(), HtyErr> {
+    let snapshot = |user: Option<&T>| user.map(serde_json::to_value).transpose().map_err(internal_error);
+
+    insert_into(audit_log::table)
+        .values((
+            audit_log::user_id.eq(id_user),
+            audit_log::action.eq(action.as_str()),
+            audit_log::actor_id.eq(&actor.user_id),
+            audit_log::request_id.eq(&actor.request_id),
+            audit_log::before.eq(snapshot(before)?),
+            audit_log::after.eq(snapshot(after)?),
+        ))
+        .execute(conn)?;
+
+    Ok(())
+}
+
+fn db_find_history(conn: &mut PgConnection, id_user: &str, params: &PageParams) -> Result<Page<AuditEntry>, HtyErr> {
+    use crate::pagination::*;
+
+    let r = audit_log::table
+        .filter(audit_log::user_id.eq(id_user.to_string()))
+        .order((audit_log::created_at.desc(), audit_log::id.desc()))
+        .into_boxed()
+        .paginate(Some(params.page))
+        .per_page(Some(params.page_size))
+        .count_by(params.count, "audit_log")
+        .load_and_count_pages::<AuditEntry>(conn)?;
+
+    Ok(r)
+}
+
+// `GET /users/{id}/history`: changes of a user, newest first. Purged users keep their history.
+pub async fn find_user_history(conn: DbConn, PathParam(id): PathParam<String>, page_params: PageParams) -> Result<Json<MyResponse<Page<AuditEntry>>>, HtyErr> {
+    if page_params.cursor.is_some() {
+        return Err(HtyErr {
+            code: HtyErrCode::CommonError,
+            reason: Some("history can't be paginated with `cursor`".to_string()),
+        });
+    }
+
+    let history = conn.interact(move |conn| db_find_history(conn, &id, &page_params)).await?;
+    debug!("find_user_history -> {} entries", history.items.len());
+
+    let resp = MyResponse {
+        r: true,
+        d: Some(history),
+        e: None,
+    };
+
+    Ok(Json(resp))
+}
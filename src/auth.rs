@@ -0,0 +1,447 @@
+use std::fmt;
+use std::ops::Deref;
+use std::sync::{Arc, LazyLock};
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use axum::extract::{FromRef, FromRequestParts, State};
+use axum::http::header::AUTHORIZATION;
+use axum::http::request::Parts;
+use axum::Json;
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::{insert_into, Connection, ExpressionMethods, OptionalExtension, PgConnection, QueryDsl, RunQueryDsl};
+use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use password_hash::rand_core::OsRng;
+use password_hash::SaltString;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use tracing::{debug, warn};
+use crate::config::JwtSettings;
+use crate::rbac::{db_find_roles, Role};
+use crate::schema::{refresh_tokens, users};
+use crate::audit::{self, Actor, AuditAction};
+use crate::{uuid, DbConn, HtyErr, HtyErrCode, JsonBody, MyResponse, TypedUser};
+
+pub struct JwtConfig {
+    encoding_key: EncodingKey,
+    decoding_key: DecodingKey,
+    // access token lifetime in seconds
+    pub expires_in: i64,
+    // refresh token lifetime in seconds
+    pub refresh_expires_in: i64,
+}
+
+pub type MyJwtState = Arc<JwtConfig>;
+
+impl JwtConfig {
+    pub fn new(settings: &JwtSettings) -> Self {
+        JwtConfig {
+            encoding_key: EncodingKey::from_secret(settings.secret.as_bytes()),
+            decoding_key: DecodingKey::from_secret(settings.secret.as_bytes()),
+            expires_in: settings.expires_in,
+            refresh_expires_in: settings.refresh_expires_in,
+        }
+    }
+
+    pub fn issue(&self, user_id: &str, username: &str, roles: Vec<Role>) -> Result<String, HtyErr> {
+        let now = Utc::now().timestamp();
+        let claims = Claims {
+            sub: user_id.to_string(),
+            username: username.to_string(),
+            roles,
+            iat: now,
+            exp: now + self.expires_in,
+        };
+
+        encode(&Header::default(), &claims, &self.encoding_key).map_err(|e| HtyErr {
+            code: HtyErrCode::JwtErr,
+            reason: Some(e.to_string()),
+        })
+    }
+
+    pub fn verify(&self, token: &str) -> Result<Claims, HtyErr> {
+        decode::<Claims>(token, &self.decoding_key, &Validation::default())
+            .map(|data| data.claims)
+            .map_err(|e| HtyErr {
+                code: HtyErrCode::JwtErr,
+                reason: Some(e.to_string()),
+            })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Claims {
+    // user id
+    pub sub: String,
+    pub username: String,
+    #[serde(default)]
+    pub roles: Vec<Role>,
+    pub iat: i64,
+    pub exp: i64,
+}
+
+// The authenticated caller, taken from a valid `Authorization: Bearer <jwt>` header.
+#[derive(Debug, Clone)]
+pub struct AuthUser(pub Claims);
+
+impl Deref for AuthUser {
+    type Target = Claims;
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<B> FromRequestParts<B> for AuthUser
+    where
+        MyJwtState: FromRef<B>,
+        B: Send + Sync, {
+    type Rejection = HtyErr;
+
+    async fn from_request_parts(parts: &mut Parts, state: &B) -> Result<Self, Self::Rejection> {
+        let header = parts.headers.get(AUTHORIZATION).ok_or(HtyErr {
+            code: HtyErrCode::AuthenticationFailed,
+            reason: Some("missing Authorization header".to_string()),
+        })?;
+
+        let token = header.to_str().ok()
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .ok_or(HtyErr {
+                code: HtyErrCode::AuthenticationFailed,
+                reason: Some("expected `Authorization: Bearer <token>`".to_string()),
+            })?;
+
+        let claims = MyJwtState::from_ref(state).verify(token.trim())?;
+        debug!("auth user -> {:?}", claims);
+
+        Ok(Self(claims))
+    }
+}
+
+const MIN_PASSWORD_LEN: usize = 8;
+
+// Verified against when the username is unknown, so both failure paths cost one argon2 run.
+static DUMMY_PASSWORD_HASH: LazyLock<String> = LazyLock::new(|| {
+    hash_password("dummy-password").expect("hash dummy password")
+});
+
+// Hashes with argon2id and a random salt, returning the PHC string stored in `users.password_hash`.
+pub fn hash_password(password: &str) -> Result<String, HtyErr> {
+    if password.chars().count() < MIN_PASSWORD_LEN {
+        return Err(HtyErr {
+            code: HtyErrCode::CommonError,
+            reason: Some(format!("password must be at least {} characters", MIN_PASSWORD_LEN)),
+        });
+    }
+
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| HtyErr {
+            code: HtyErrCode::InternalErr,
+            reason: Some(e.to_string()),
+        })
+}
+
+// argon2 compares the derived hashes in constant time, see `password_hash::Output`.
+pub fn verify_password(password: &str, some_hash: Option<&str>) -> Result<(), HtyErr> {
+    let hash = some_hash.unwrap_or(DUMMY_PASSWORD_HASH.as_str());
+    let verified = PasswordHash::new(hash)
+        .map(|parsed| Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok())
+        .unwrap_or(false);
+
+    if verified && some_hash.is_some() {
+        Ok(())
+    } else {
+        Err(HtyErr {
+            code: HtyErrCode::AuthenticationFailed,
+            reason: Some("invalid credentials".to_string()),
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ReqLogin {
+    pub username: Option<String>,
+    #[serde(skip_serializing)]
+    pub password: Option<String>,
+}
+
+impl fmt::Debug for ReqLogin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReqLogin")
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| "<redacted>"))
+            .finish()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ReqChangePassword {
+    pub old_password: Option<String>,
+    pub new_password: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReqRefreshToken {
+    pub refresh_token: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RespToken {
+    pub access_token: String,
+    pub token_type: String,
+    pub expires_in: i64,
+    pub refresh_token: String,
+}
+
+#[derive(Queryable, Identifiable, Debug, Clone)]
+#[diesel(table_name = refresh_tokens)]
+pub struct RefreshToken {
+    pub id: String,
+    pub token_hash: String,
+    pub user_id: String,
+    pub family_id: String,
+    pub expires_at: NaiveDateTime,
+    pub revoked: bool,
+    pub created_at: NaiveDateTime,
+}
+
+fn hash_token(raw: &str) -> String {
+    format!("{:x}", Sha256::digest(raw.as_bytes()))
+}
+
+fn invalid_refresh_token(reason: &str) -> HtyErr {
+    HtyErr {
+        code: HtyErrCode::AuthenticationFailed,
+        reason: Some(reason.to_string()),
+    }
+}
+
+impl RefreshToken {
+    // Stores a new token in `id_family` and returns the raw value, which is only ever seen by the client.
+    pub fn db_issue(conn: &mut PgConnection, id_user: &str, id_family: &str, ttl_secs: i64) -> Result<String, HtyErr> {
+        use crate::schema::refresh_tokens::dsl::*;
+
+        // two v4 uuids give 244 random bits
+        let raw = format!("{}{}", ::uuid::Uuid::new_v4().simple(), ::uuid::Uuid::new_v4().simple());
+
+        insert_into(refresh_tokens)
+            .values((
+                id.eq(uuid()),
+                token_hash.eq(hash_token(&raw)),
+                user_id.eq(id_user),
+                family_id.eq(id_family),
+                expires_at.eq(Utc::now().naive_utc() + Duration::seconds(ttl_secs)),
+            ))
+            .execute(conn)?;
+
+        Ok(raw)
+    }
+
+    // Revokes `raw` and issues its successor in the same family, returning `(user_id, new raw token)`.
+    // Presenting an already rotated token revokes the whole family, since it has most likely leaked.
+    pub fn db_rotate(conn: &mut PgConnection, raw: &str, ttl_secs: i64) -> Result<(String, String), HtyErr> {
+        use crate::schema::refresh_tokens::dsl::*;
+
+        // reuse detection must commit the family revocation, so it is reported as `Ok(None)` here
+        let rotated = conn.transaction::<_, HtyErr, _>(|conn| {
+            let current = refresh_tokens
+                .filter(token_hash.eq(hash_token(raw)))
+                .for_update()
+                .first::<RefreshToken>(conn)
+                .optional()?
+                .ok_or(invalid_refresh_token("invalid refresh token"))?;
+
+            if current.revoked {
+                warn!("refresh token reuse detected -> user: {}, family: {}", current.user_id, current.family_id);
+                Self::db_revoke_family(conn, &current.family_id)?;
+                return Ok(None);
+            }
+
+            if current.expires_at < Utc::now().naive_utc() {
+                return Err(invalid_refresh_token("refresh token expired"));
+            }
+
+            diesel::update(refresh_tokens.find(&current.id))
+                .set(revoked.eq(true))
+                .execute(conn)?;
+
+            let next = Self::db_issue(conn, &current.user_id, &current.family_id, ttl_secs)?;
+            Ok(Some((current.user_id, next)))
+        })?;
+
+        rotated.ok_or(invalid_refresh_token("refresh token reuse detected"))
+    }
+
+    // Revokes every token of the family `raw` belongs to. Unknown tokens are ignored.
+    pub fn db_revoke(conn: &mut PgConnection, raw: &str) -> Result<(), HtyErr> {
+        use crate::schema::refresh_tokens::dsl::*;
+
+        let some_family = refresh_tokens
+            .filter(token_hash.eq(hash_token(raw)))
+            .select(family_id)
+            .first::<String>(conn)
+            .optional()?;
+
+        if let Some(id_family) = some_family {
+            Self::db_revoke_family(conn, &id_family)?;
+        }
+        Ok(())
+    }
+
+    pub fn db_revoke_user(conn: &mut PgConnection, id_user: &str) -> Result<usize, HtyErr> {
+        use crate::schema::refresh_tokens::dsl::*;
+
+        diesel::update(refresh_tokens.filter(user_id.eq(id_user)))
+            .set(revoked.eq(true))
+            .execute(conn)
+            .map_err(HtyErr::from)
+    }
+
+    fn db_revoke_family(conn: &mut PgConnection, id_family: &str) -> Result<usize, HtyErr> {
+        use crate::schema::refresh_tokens::dsl::*;
+
+        diesel::update(refresh_tokens.filter(family_id.eq(id_family)))
+            .set(revoked.eq(true))
+            .execute(conn)
+            .map_err(HtyErr::from)
+    }
+}
+
+pub async fn login(
+    State(jwt): State<MyJwtState>,
+    conn: DbConn,
+    JsonBody(payload): JsonBody<ReqLogin>) -> Result<Json<MyResponse<RespToken>>, HtyErr> {
+    let in_username = payload.username.ok_or(HtyErr {
+        code: HtyErrCode::NullErr,
+        reason: Some("username is required".to_string()),
+    })?;
+    let password = payload.password.ok_or(HtyErr {
+        code: HtyErrCode::NullErr,
+        reason: Some("password is required".to_string()),
+    })?;
+
+    let refresh_expires_in = jwt.refresh_expires_in;
+    let (id_user, username, roles, refresh_token) = conn.interact(move |conn| {
+        let some_user = users::table
+            .filter(users::username.eq(&in_username))
+            .filter(users::deleted_at.is_null())
+            .select((users::id, users::username, users::password_hash))
+            .first::<(String, String, Option<String>)>(conn)
+            .optional()?;
+
+        verify_password(&password, some_user.as_ref().and_then(|u| u.2.as_deref()))?;
+        let (id_user, username, _) = some_user.ok_or(HtyErr {
+            code: HtyErrCode::AuthenticationFailed,
+            reason: Some("invalid credentials".to_string()),
+        })?;
+
+        // every login starts a new token family
+        let refresh_token = RefreshToken::db_issue(conn, &id_user, &uuid(), refresh_expires_in)?;
+        let roles = db_find_roles(conn, &id_user)?;
+        Ok((id_user, username, roles, refresh_token))
+    }).await?;
+
+    token_response(&jwt, &id_user, &username, roles, refresh_token)
+}
+
+// `POST /token/refresh`: exchanges a refresh token for a new access token and a rotated refresh token.
+pub async fn refresh(
+    State(jwt): State<MyJwtState>,
+    conn: DbConn,
+    JsonBody(payload): JsonBody<ReqRefreshToken>) -> Result<Json<MyResponse<RespToken>>, HtyErr> {
+    let raw = payload.refresh_token.ok_or(HtyErr {
+        code: HtyErrCode::NullErr,
+        reason: Some("refresh_token is required".to_string()),
+    })?;
+
+    let refresh_expires_in = jwt.refresh_expires_in;
+    let (id_user, username, roles, refresh_token) = conn.interact(move |conn| {
+        let (id_user, refresh_token) = RefreshToken::db_rotate(conn, &raw, refresh_expires_in)?;
+        let username = users::table
+            .find(&id_user)
+            .filter(users::deleted_at.is_null())
+            .select(users::username)
+            .first::<String>(conn)?;
+        let roles = db_find_roles(conn, &id_user)?;
+        Ok((id_user, username, roles, refresh_token))
+    }).await?;
+
+    token_response(&jwt, &id_user, &username, roles, refresh_token)
+}
+
+// `POST /logout`: revokes the presented refresh token together with its family.
+pub async fn logout(
+    conn: DbConn,
+    JsonBody(payload): JsonBody<ReqRefreshToken>) -> Result<Json<MyResponse<()>>, HtyErr> {
+    let raw = payload.refresh_token.ok_or(HtyErr {
+        code: HtyErrCode::NullErr,
+        reason: Some("refresh_token is required".to_string()),
+    })?;
+
+    conn.interact(move |conn| RefreshToken::db_revoke(conn, &raw)).await?;
+
+    let resp = MyResponse {
+        r: true,
+        d: None,
+        e: None,
+    };
+
+    Ok(Json(resp))
+}
+
+// `PUT /users/me/password`: the caller proves the old password, all their refresh tokens are revoked.
+pub async fn change_password(
+    auth_user: AuthUser,
+    actor: Actor,
+    conn: DbConn,
+    JsonBody(payload): JsonBody<ReqChangePassword>) -> Result<Json<MyResponse<()>>, HtyErr> {
+    let (old_password, new_password) = payload.old_password.zip(payload.new_password).ok_or(HtyErr {
+        code: HtyErrCode::NullErr,
+        reason: Some("old_password and new_password are required".to_string()),
+    })?;
+
+    conn.interact(move |conn| {
+        conn.transaction(|conn| {
+            // the snapshots leave out the hash, the audit entry records that it changed
+            let before = users::table
+                .find(&auth_user.sub)
+                .filter(users::deleted_at.is_null())
+                .for_update()
+                .first::<TypedUser<Value>>(conn)?;
+
+            verify_password(&old_password, before.password_hash.as_deref())?;
+
+            let after = diesel::update(users::table.find(&auth_user.sub))
+                .set(users::password_hash.eq(hash_password(&new_password)?))
+                .get_result::<TypedUser<Value>>(conn)?;
+
+            RefreshToken::db_revoke_user(conn, &auth_user.sub)?;
+            audit::db_record(conn, &actor, AuditAction::PasswordChange, &auth_user.sub, Some(&before), Some(&after))?;
+            Ok(())
+        })
+    }).await?;
+
+    let resp = MyResponse {
+        r: true,
+        d: None,
+        e: None,
+    };
+
+    Ok(Json(resp))
+}
+
+fn token_response(jwt: &JwtConfig, id_user: &str, username: &str, roles: Vec<Role>, refresh_token: String) -> Result<Json<MyResponse<RespToken>>, HtyErr> {
+    let resp = MyResponse {
+        r: true,
+        d: Some(RespToken {
+            access_token: jwt.issue(id_user, username, roles)?,
+            token_type: "Bearer".to_string(),
+            expires_in: jwt.expires_in,
+            refresh_token,
+        }),
+        e: None,
+    };
+
+    Ok(Json(resp))
+}
@@ -0,0 +1,324 @@
+use std::collections::{HashMap, HashSet};
+use axum::extract::FromRequestParts;
+use axum::http::request::Parts;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use diesel::dsl::now;
+use diesel::{insert_into, Connection, ExpressionMethods, PgConnection, QueryDsl, RunQueryDsl};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+use crate::audit::{Actor, AuditAction};
+use crate::auth::RefreshToken;
+use crate::schema::users;
+use crate::{audit, auth, new_typed_user, query_params, DbConn, HtyErr, HtyErrCode, JsonBody, MyResponse, NewTypedUser, ReqPatchUser, ReqUser, ReqWxMessageData4KeywordTemplate, TypedUser};
+
+// Upper bound on the items of one bulk request, every password is hashed with Argon2.
+const MAX_BULK_ITEMS: usize = 100;
+
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BulkMode {
+    // any failed item rolls the whole batch back
+    #[default]
+    AllOrNothing,
+    // failed items are reported, the others are committed
+    BestEffort,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct BulkParams {
+    #[serde(default)]
+    pub mode: BulkMode,
+}
+
+impl<S> FromRequestParts<S> for BulkParams
+    where
+        S: Send + Sync, {
+    type Rejection = HtyErr;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        query_params(parts)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BulkStatus {
+    Created,
+    Updated,
+    Deleted,
+    Conflict,
+    NotFound,
+    Invalid,
+    // would have succeeded, but another item failed in all-or-nothing mode
+    RolledBack,
+}
+
+impl BulkStatus {
+    fn is_applied(&self) -> bool {
+        matches!(self, BulkStatus::Created | BulkStatus::Updated | BulkStatus::Deleted)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BulkItem {
+    // position in the request array
+    pub index: usize,
+    pub id: Option<String>,
+    pub status: BulkStatus,
+    pub error: Option<String>,
+}
+
+impl BulkItem {
+    fn new(index: usize, id: Option<String>, status: BulkStatus) -> Self {
+        BulkItem {
+            index,
+            id,
+            status,
+            error: None,
+        }
+    }
+
+    // per-item failures are reported, anything else (e.g. a lost connection) fails the request
+    fn failed(index: usize, id: Option<String>, err: HtyErr) -> Result<Self, HtyErr> {
+        let status = match err.code {
+            HtyErrCode::ConflictErr => BulkStatus::Conflict,
+            HtyErrCode::NotFoundErr => BulkStatus::NotFound,
+            HtyErrCode::CommonError | HtyErrCode::NullErr | HtyErrCode::ConstraintErr => BulkStatus::Invalid,
+            _ => return Err(err),
+        };
+        Ok(BulkItem {
+            index,
+            id,
+            status,
+            error: err.reason,
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BulkResult {
+    pub committed: bool,
+    pub items: Vec<BulkItem>,
+}
+
+enum BatchErr {
+    Rollback,
+    Err(HtyErr),
+}
+
+impl From<HtyErr> for BatchErr {
+    fn from(err: HtyErr) -> Self {
+        BatchErr::Err(err)
+    }
+}
+
+impl From<diesel::result::Error> for BatchErr {
+    fn from(err: diesel::result::Error) -> Self {
+        BatchErr::Err(err.into())
+    }
+}
+
+// Runs `apply` in one transaction, which is rolled back in all-or-nothing mode as soon as any item failed.
+fn run_batch<F>(conn: &mut PgConnection, mode: BulkMode, apply: F) -> Result<BulkResult, HtyErr>
+    where
+        F: FnOnce(&mut PgConnection) -> Result<Vec<BulkItem>, HtyErr>,
+{
+    let mut items = Vec::new();
+    let r = conn.transaction::<_, BatchErr, _>(|conn| {
+        items = apply(conn)?;
+        items.sort_by_key(|item| item.index);
+        if mode == BulkMode::AllOrNothing && items.iter().any(|item| !item.status.is_applied()) {
+            return Err(BatchErr::Rollback);
+        }
+        Ok(())
+    });
+
+    match r {
+        Ok(()) => Ok(BulkResult { committed: true, items }),
+        Err(BatchErr::Rollback) => {
+            for item in items.iter_mut().filter(|item| item.status.is_applied()) {
+                item.status = BulkStatus::RolledBack;
+            }
+            Ok(BulkResult { committed: false, items })
+        }
+        Err(BatchErr::Err(err)) => Err(err),
+    }
+}
+
+fn check_len<T>(payload: &[T]) -> Result<(), HtyErr> {
+    if payload.len() > MAX_BULK_ITEMS {
+        return Err(HtyErr {
+            code: HtyErrCode::CommonError,
+            reason: Some(format!("at most {} items per bulk request, got {}", MAX_BULK_ITEMS, payload.len())),
+        });
+    }
+    Ok(())
+}
+
+// 200 once committed, a rolled back all-or-nothing batch is a 422 that still lists every item.
+fn bulk_response(result: BulkResult) -> Response {
+    if result.committed {
+        let resp = MyResponse {
+            r: true,
+            d: Some(result),
+            e: None,
+        };
+        return (StatusCode::OK, Json(resp)).into_response();
+    }
+
+    let failed = result.items.iter().filter(|item| item.status != BulkStatus::RolledBack).count();
+    let resp = MyResponse {
+        r: false,
+        e: Some(format!("{} of {} items failed, nothing was applied", failed, result.items.len())),
+        d: Some(result),
+    };
+    (StatusCode::UNPROCESSABLE_ENTITY, Json(resp)).into_response()
+}
+
+// `POST /users/bulk`: a single multi-row insert, usernames that already exist are reported as conflicts.
+pub async fn create_users(
+    conn: DbConn,
+    params: BulkParams,
+    actor: Actor,
+    JsonBody(payload): JsonBody<Vec<ReqUser>>) -> Result<Response, HtyErr> {
+    check_len(&payload)?;
+
+    let result = conn.interact(move |conn| {
+        let mut items = Vec::new();
+        let mut in_users = Vec::new();
+        for (index, req) in payload.iter().enumerate() {
+            // argon2 is CPU bound, so hash on the blocking pool as well
+            let in_user = new_typed_user(req).and_then(|mut in_user| {
+                in_user.password_hash = req.password.as_deref().map(auth::hash_password).transpose()?;
+                Ok(in_user)
+            });
+            match in_user {
+                Ok(in_user) => in_users.push((index, in_user)),
+                Err(err) => items.push(BulkItem::failed(index, None, err)?),
+            }
+        }
+
+        run_batch(conn, params.mode, |conn| {
+            let rows: Vec<NewTypedUser<ReqWxMessageData4KeywordTemplate>> = in_users.iter().map(|(_, u)| u.clone()).collect();
+            // rows skipped by `ON CONFLICT DO NOTHING` clash with `users_username_uindex`,
+            // possibly with an earlier item of the same batch
+            let created: Vec<TypedUser<ReqWxMessageData4KeywordTemplate>> = if rows.is_empty() {
+                Vec::new()
+            } else {
+                insert_into(users::table)
+                    .values(rows)
+                    .on_conflict_do_nothing()
+                    .get_results(conn)?
+            };
+            for created_user in &created {
+                audit::db_record(conn, &actor, AuditAction::Create, &created_user.id, None, Some(created_user))?;
+            }
+            let created: HashSet<String> = created.into_iter().map(|u| u.id).collect();
+
+            for (index, in_user) in in_users {
+                if created.contains(&in_user.id) {
+                    items.push(BulkItem::new(index, Some(in_user.id), BulkStatus::Created));
+                } else {
+                    items.push(BulkItem::failed(index, None, HtyErr {
+                        code: HtyErrCode::ConflictErr,
+                        reason: Some(format!("username {} already exists", in_user.username)),
+                    })?);
+                }
+            }
+            Ok(items)
+        })
+    }).await?;
+
+    debug!("create_users -> committed: {}, {} items", result.committed, result.items.len());
+    Ok(bulk_response(result))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReqBulkPatchUser {
+    pub id: Option<String>,
+    #[serde(flatten)]
+    pub patch: ReqPatchUser,
+}
+
+// `PATCH /users/bulk`: the same merge as `PATCH /users/{id}` for each item, each one under its own savepoint.
+pub async fn patch_users(
+    conn: DbConn,
+    params: BulkParams,
+    actor: Actor,
+    JsonBody(payload): JsonBody<Vec<ReqBulkPatchUser>>) -> Result<Response, HtyErr> {
+    check_len(&payload)?;
+
+    let result = conn.interact(move |conn| {
+        run_batch(conn, params.mode, |conn| {
+            let mut items = Vec::new();
+            for (index, req) in payload.iter().enumerate() {
+                let Some(id) = req.id.clone() else {
+                    items.push(BulkItem::failed(index, None, HtyErr {
+                        code: HtyErrCode::NullErr,
+                        reason: Some("id is required".to_string()),
+                    })?);
+                    continue;
+                };
+
+                // `db_patch_typed_user` opens a nested transaction, so a failed item doesn't abort the batch
+                match TypedUser::<ReqWxMessageData4KeywordTemplate>::db_patch_typed_user(conn, &id, &req.patch, &None, &actor) {
+                    Ok(_) => items.push(BulkItem::new(index, Some(id), BulkStatus::Updated)),
+                    Err(err) => items.push(BulkItem::failed(index, Some(id), err)?),
+                }
+            }
+            Ok(items)
+        })
+    }).await?;
+
+    Ok(bulk_response(result))
+}
+
+// `DELETE /users/bulk`: soft deletes a list of ids in one statement, unknown or already deleted ids are reported
+// as not found.
+pub async fn delete_users(
+    conn: DbConn,
+    params: BulkParams,
+    actor: Actor,
+    JsonBody(payload): JsonBody<Vec<String>>) -> Result<Response, HtyErr> {
+    check_len(&payload)?;
+
+    let result = conn.interact(move |conn| {
+        run_batch(conn, params.mode, |conn| {
+            let before: HashMap<String, TypedUser<ReqWxMessageData4KeywordTemplate>> = users::table
+                .filter(users::id.eq_any(&payload))
+                .filter(users::deleted_at.is_null())
+                .for_update()
+                .load::<TypedUser<ReqWxMessageData4KeywordTemplate>>(conn)?
+                .into_iter()
+                .map(|u| (u.id.clone(), u))
+                .collect();
+            let deleted_users = diesel::update(users::table.filter(users::id.eq_any(before.keys())))
+                .set(users::deleted_at.eq(now))
+                .get_results::<TypedUser<ReqWxMessageData4KeywordTemplate>>(conn)?;
+            for deleted_user in &deleted_users {
+                RefreshToken::db_revoke_user(conn, &deleted_user.id)?;
+                audit::db_record(conn, &actor, AuditAction::Delete, &deleted_user.id, before.get(&deleted_user.id), Some(deleted_user))?;
+            }
+            let deleted: HashSet<String> = deleted_users.into_iter().map(|u| u.id).collect();
+
+            let mut seen = HashSet::new();
+            payload.iter().enumerate()
+                .map(|(index, id)| {
+                    // an id listed twice is only deleted once
+                    if deleted.contains(id) && seen.insert(id) {
+                        Ok(BulkItem::new(index, Some(id.clone()), BulkStatus::Deleted))
+                    } else {
+                        BulkItem::failed(index, Some(id.clone()), HtyErr {
+                            code: HtyErrCode::NotFoundErr,
+                            reason: Some(format!("user {} not found", id)),
+                        })
+                    }
+                })
+                .collect()
+        })
+    }).await?;
+
+    Ok(bulk_response(result))
+}
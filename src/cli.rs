@@ -0,0 +1,238 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use clap::{Args, Parser, Subcommand};
+use diesel::sql_types::Text;
+use diesel::{PgConnection, RunQueryDsl};
+use serde_json::json;
+use crate::audit::Actor;
+use crate::filter::UserFilter;
+use crate::pagination::PageParams;
+use crate::rbac::Role;
+use crate::transaction::{db_transaction, Isolation};
+use crate::{auth, db_create_typed_user, migrate, new_typed_user, paginate_users, rbac, uuid, DbConn, HtyErr, HtyErrCode, MyDbState, ReqUser, ReqWxMessageData4KeywordTemplate, TypedUser};
+
+// Users inserted per transaction by `seed`.
+const SEED_CHUNK: usize = 500;
+
+/// Axum + Diesel playground: the HTTP server and its admin commands
+#[derive(Debug, Parser)]
+#[command(version)]
+pub struct Cli {
+    /// TOML file with settings, overridden by `.env` and the environment [default: ./config.toml if present]
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+    // `serve` when omitted
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Start the HTTP server (the default)
+    Serve(ServeArgs),
+    /// Apply, revert or list migrations
+    #[command(subcommand)]
+    Migrate(MigrateCommand),
+    /// Manage users without going through the API
+    #[command(subcommand)]
+    User(UserCommand),
+    /// Insert generated users, for local testing
+    Seed {
+        #[arg(long, default_value_t = 100)]
+        count: usize,
+    },
+    /// Check that the database is reachable and fully migrated
+    CheckDb,
+}
+
+#[derive(Debug, Default, Args)]
+pub struct ServeArgs {
+    /// Address to listen on, overrides `BIND_ADDR` [default: 127.0.0.1:3000]
+    #[arg(long)]
+    pub addr: Option<SocketAddr>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum MigrateCommand {
+    /// Apply every pending migration
+    Up,
+    /// Revert the most recently applied migration
+    Down,
+    /// List migrations and whether they are applied
+    Status,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum UserCommand {
+    /// Create a user, optionally with a password and roles
+    Create {
+        #[arg(long)]
+        username: String,
+        #[arg(long)]
+        password: Option<String>,
+        /// admin, operator or viewer, can be repeated
+        #[arg(long = "role", value_parser = parse_role)]
+        roles: Vec<Role>,
+    },
+    /// Soft delete a user, see `purge`
+    Delete {
+        id: String,
+    },
+    /// List users, newest first
+    List {
+        #[arg(long, default_value_t = 1)]
+        page: i64,
+        #[arg(long, default_value_t = 10)]
+        page_size: i64,
+        #[arg(long)]
+        include_deleted: bool,
+    },
+    /// Print a user and their roles as JSON
+    Show {
+        id: String,
+    },
+}
+
+fn parse_role(name: &str) -> Result<Role, String> {
+    Role::from_name(name).ok_or_else(|| format!("unknown role `{}`, expected admin, operator or viewer", name))
+}
+
+// Changes made from the command line have no token, the request id tells them apart in the audit log.
+fn cli_actor() -> Actor {
+    Actor {
+        user_id: None,
+        request_id: format!("cli-{}", uuid()),
+    }
+}
+
+// Every command except `serve`, output goes to stdout and failures become a non-zero exit status in `main`.
+pub async fn run(command: Command, db: MyDbState) -> Result<(), HtyErr> {
+    let conn = DbConn::acquire(db).await?;
+
+    match command {
+        Command::Serve(_) => unreachable!("`serve` is handled by `main`"),
+        Command::Migrate(MigrateCommand::Up) => {
+            let applied = conn.interact(migrate::db_run_pending_migrations).await?;
+            println!("applied {} migrations", applied.len());
+        }
+        Command::Migrate(MigrateCommand::Down) => {
+            let reverted = conn.interact(migrate::db_revert_last_migration).await?;
+            println!("reverted {}", reverted);
+        }
+        Command::Migrate(MigrateCommand::Status) => {
+            let status = conn.interact(migrate::db_migration_status).await?;
+            for (name, applied) in &status.known {
+                println!("[{}] {}", if *applied { "X" } else { " " }, name);
+            }
+            for version in &status.unknown {
+                println!("[?] {} (unknown to this binary)", version);
+            }
+        }
+        Command::User(UserCommand::Create { username, password, roles }) => {
+            let created_user = conn.interact(move |conn| {
+                let mut in_user = new_typed_user(&ReqUser {
+                    id: None,
+                    username: Some(username),
+                    created_at: None,
+                    meta: None,
+                    password: None,
+                })?;
+                in_user.password_hash = password.as_deref().map(auth::hash_password).transpose()?;
+
+                let actor = cli_actor();
+                db_transaction(conn, Isolation::ReadCommitted, |conn| {
+                    let created_user = db_create_typed_user::<ReqWxMessageData4KeywordTemplate, ReqWxMessageData4KeywordTemplate>(conn, &in_user, &actor)?;
+                    if !roles.is_empty() {
+                        rbac::db_set_roles(conn, &created_user.id, &roles)?;
+                    }
+                    Ok(created_user)
+                })
+            }).await?;
+            println!("created user {} ({})", created_user.id, created_user.username);
+        }
+        Command::User(UserCommand::Delete { id }) => {
+            let deleted_user = conn.interact(move |conn| {
+                let actor = cli_actor();
+                db_transaction(conn, Isolation::ReadCommitted, |conn| {
+                    TypedUser::<ReqWxMessageData4KeywordTemplate>::db_delete_typed_user::<ReqWxMessageData4KeywordTemplate>(conn, &id, &None, &actor)
+                })
+            }).await?;
+            println!("deleted user {} ({})", deleted_user.id, deleted_user.username);
+        }
+        Command::User(UserCommand::List { page, page_size, include_deleted }) => {
+            let params = PageParams {
+                page,
+                page_size,
+                count: None,
+                cursor: None,
+            }.validate()?;
+            let filter = UserFilter {
+                include_deleted,
+                ..Default::default()
+            };
+
+            let users = conn.interact(move |conn| paginate_users::<ReqWxMessageData4KeywordTemplate>(&params, &filter, conn)).await?;
+            for user in &users.items {
+                let deleted = if user.deleted_at.is_some() { "  (deleted)" } else { "" };
+                println!("{}  {}  {}{}", user.id, user.created_at.to_rfc3339(), user.username, deleted);
+            }
+            println!("page {} of {}, {} users", users.page, users.total_pages.unwrap_or_default(), users.total.unwrap_or_default());
+        }
+        Command::User(UserCommand::Show { id }) => {
+            let (user, roles) = conn.interact(move |conn| {
+                let user = TypedUser::<ReqWxMessageData4KeywordTemplate>::find_typed_user_by_id(&id, true, conn)?;
+                let roles = rbac::db_find_roles(conn, &id)?;
+                Ok((user, roles))
+            }).await?;
+            let shown = json!({"user": user, "roles": roles});
+            println!("{}", serde_json::to_string_pretty(&shown).map_err(crate::internal_error)?);
+        }
+        Command::Seed { count } => {
+            let seeded = conn.interact(move |conn| db_seed_users(conn, count)).await?;
+            println!("seeded {} users", seeded);
+        }
+        Command::CheckDb => {
+            let (version, status) = conn.interact(|conn| {
+                let version = diesel::select(diesel::dsl::sql::<Text>("version()")).get_result::<String>(conn)?;
+                Ok((version, migrate::db_migration_status(conn)?))
+            }).await?;
+            println!("connected: {}", version);
+            println!("migrations: {} applied, {} pending, {} unknown", status.known.len() - status.pending(), status.pending(), status.unknown.len());
+
+            if status.pending() > 0 || !status.unknown.is_empty() {
+                return Err(HtyErr {
+                    code: HtyErrCode::DbErr,
+                    reason: Some("schema doesn't match this binary, see `migrate status`".to_string()),
+                });
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// `seed_<random>` users with the default `meta` and no password, inserted in chunks of `SEED_CHUNK`.
+fn db_seed_users(conn: &mut PgConnection, count: usize) -> Result<usize, HtyErr> {
+    let actor = cli_actor();
+    let mut seeded = 0;
+
+    while seeded < count {
+        let chunk = SEED_CHUNK.min(count - seeded);
+        db_transaction(conn, Isolation::ReadCommitted, |conn| {
+            for _ in 0..chunk {
+                let in_user = new_typed_user(&ReqUser {
+                    id: None,
+                    username: Some(format!("seed_{}", &uuid()[..13])),
+                    created_at: None,
+                    meta: None,
+                    password: None,
+                })?;
+                db_create_typed_user::<ReqWxMessageData4KeywordTemplate, ReqWxMessageData4KeywordTemplate>(conn, &in_user, &actor)?;
+            }
+            Ok(())
+        })?;
+        seeded += chunk;
+    }
+
+    Ok(seeded)
+}
@@ -0,0 +1,313 @@
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+use time::macros::format_description;
+use time::UtcOffset;
+use tracing::Level;
+
+// Read when it exists and no other file is given with `--config` or `CONFIG_FILE`.
+const DEFAULT_CONFIG_FILE: &str = "config.toml";
+
+// Example `JWT_SECRET`s from old READMEs and `.env` files, anyone can sign tokens with them.
+const PLACEHOLDER_SECRETS: [&str; 3] = ["change-me-in-production", "change-me", "secret"];
+
+// Every setting by its environment variable. In the TOML file the same names are written as lowercase paths,
+// `[pool] size = 5` sets `POOL_SIZE`.
+const KEYS: [&str; 22] = [
+    "DATABASE_URL",
+    "BIND_ADDR",
+    "LOG_LEVEL",
+    "LOG_UTC_OFFSET",
+    "RUN_MIGRATIONS",
+    "STARTUP_DB_DEADLINE_SECS",
+    "STARTUP_DEGRADED",
+    "POOL_SIZE",
+    "POOL_ACQUIRE_TIMEOUT_SECS",
+    "POOL_MIN_IDLE",
+    "POOL_CONNECTION_TIMEOUT_SECS",
+    "POOL_IDLE_TIMEOUT_SECS",
+    "POOL_MAX_LIFETIME_SECS",
+    "POOL_TEST_ON_CHECK_OUT",
+    "SESSION_STATEMENT_TIMEOUT_MS",
+    "SESSION_APPLICATION_NAME",
+    "SESSION_SEARCH_PATH",
+    "JWT_SECRET",
+    "JWT_EXPIRES_SECS",
+    "REFRESH_TOKEN_EXPIRES_SECS",
+    "SOFT_DELETE_RETENTION_DAYS",
+    "PURGE_INTERVAL_SECS",
+];
+
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    pub size: u32,
+    // how long a request waits for a pooled connection before giving up with 503
+    pub acquire_timeout: Duration,
+    // idle connections kept open, `None` keeps `size` of them
+    pub min_idle: Option<u32>,
+    // how long building the pool waits for its first connections
+    pub connection_timeout: Duration,
+    // idle connections are closed after this long, `None` never closes them
+    pub idle_timeout: Option<Duration>,
+    // connections are closed at this age once returned, `None` keeps them
+    pub max_lifetime: Option<Duration>,
+    // run a health check before handing out a connection
+    pub test_on_check_out: bool,
+}
+
+// How long startup waits for the database, see `startup::wait_for_db`.
+#[derive(Debug, Clone)]
+pub struct StartupConfig {
+    // retried with exponential backoff until this much time has passed
+    pub db_deadline: Duration,
+    // when the database stays unreachable, serve anyway and answer 503 on routes that need it
+    pub degraded: bool,
+}
+
+// Set on every new connection, see `pool::ConnectionSetup`.
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    // statements running longer are cancelled, 0 disables the limit
+    pub statement_timeout_ms: u64,
+    // shown in `pg_stat_activity`
+    pub application_name: String,
+    // the server's default when `None`
+    pub search_path: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct JwtSettings {
+    pub secret: String,
+    // access token lifetime in seconds
+    pub expires_in: i64,
+    // refresh token lifetime in seconds
+    pub refresh_expires_in: i64,
+}
+
+impl fmt::Debug for JwtSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JwtSettings")
+            .field("secret", &"<redacted>")
+            .field("expires_in", &self.expires_in)
+            .field("refresh_expires_in", &self.refresh_expires_in)
+            .finish()
+    }
+}
+
+// Settings of the server and the admin commands, see `AppConfig::load` for where they come from.
+#[derive(Clone)]
+pub struct AppConfig {
+    pub database_url: String,
+    pub bind_addr: SocketAddr,
+    pub log_level: Level,
+    // offset of the log timestamps
+    pub log_utc_offset: UtcOffset,
+    // apply pending migrations when the server starts, see `migrate`
+    pub run_migrations: bool,
+    pub startup: StartupConfig,
+    pub pool: PoolConfig,
+    pub session: SessionConfig,
+    pub jwt: JwtSettings,
+    // soft-deleted users can be restored for this many days, then the purge job removes them for good
+    pub soft_delete_retention_days: i32,
+    pub purge_interval: Duration,
+}
+
+impl fmt::Debug for AppConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // the URL usually carries the password
+        f.debug_struct("AppConfig")
+            .field("database_url", &"<redacted>")
+            .field("bind_addr", &self.bind_addr)
+            .field("log_level", &self.log_level)
+            .field("log_utc_offset", &self.log_utc_offset)
+            .field("run_migrations", &self.run_migrations)
+            .field("startup", &self.startup)
+            .field("pool", &self.pool)
+            .field("session", &self.session)
+            .field("jwt", &self.jwt)
+            .field("soft_delete_retention_days", &self.soft_delete_retention_days)
+            .field("purge_interval", &self.purge_interval)
+            .finish()
+    }
+}
+
+// Every invalid setting, reported together so they can be fixed in one go.
+#[derive(Debug, thiserror::Error)]
+pub struct ConfigError(pub Vec<String>);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid configuration:")?;
+        for err in &self.0 {
+            write!(f, "\n  {}", err)?;
+        }
+        Ok(())
+    }
+}
+
+// Raw values by key and where they came from, plus the errors found while reading them.
+#[derive(Default)]
+struct Layers {
+    values: HashMap<String, (String, String)>,
+    errors: Vec<String>,
+}
+
+impl Layers {
+    // `[pool] size = 5` becomes `POOL_SIZE`, unknown keys are errors
+    fn add_toml(&mut self, file: &Path, table: &toml::Table, prefix: &str) {
+        for (name, value) in table {
+            let key = format!("{}{}", prefix, name.to_uppercase());
+            let raw = match value {
+                toml::Value::Table(nested) => {
+                    self.add_toml(file, nested, &format!("{}_", key));
+                    continue;
+                }
+                toml::Value::String(s) => s.clone(),
+                toml::Value::Integer(i) => i.to_string(),
+                toml::Value::Float(x) => x.to_string(),
+                toml::Value::Boolean(b) => b.to_string(),
+                _ => {
+                    self.errors.push(format!("{} ({}): expected a string, number or boolean", key, file.display()));
+                    continue;
+                }
+            };
+
+            if KEYS.contains(&key.as_str()) {
+                self.values.insert(key, (raw, file.display().to_string()));
+            } else {
+                self.errors.push(format!("{} ({}): unknown setting", key, file.display()));
+            }
+        }
+    }
+
+    fn add_file(&mut self, file: &Path) {
+        let table = std::fs::read_to_string(file)
+            .map_err(|e| e.to_string())
+            .and_then(|content| content.parse::<toml::Table>().map_err(|e| e.to_string()));
+        match table {
+            Ok(table) => self.add_toml(file, &table, ""),
+            Err(e) => self.errors.push(format!("{}: {}", file.display(), e)),
+        }
+    }
+
+    // `.env` is already in the environment, see `dotenv` in `main`
+    fn add_env(&mut self) {
+        for key in KEYS {
+            if let Ok(raw) = env::var(key) {
+                self.values.insert(key.to_string(), (raw, "environment".to_string()));
+            }
+        }
+    }
+
+    fn parse<T>(&mut self, key: &str, default: T, expected: &str, parse: impl Fn(&str) -> Option<T>) -> T {
+        let Some((raw, source)) = self.values.get(key) else {
+            return default;
+        };
+        match parse(raw.trim()) {
+            Some(value) => value,
+            None => {
+                self.errors.push(format!("{} ({}): expected {}, got `{}`", key, source, expected, raw));
+                default
+            }
+        }
+    }
+
+    // bounded both ways, durations beyond `max` overflow further down (`Instant`, `chrono`, Postgres)
+    fn number<T: FromStr + PartialOrd + fmt::Display>(&mut self, key: &str, default: T, min: T, max: T) -> T {
+        let expected = format!("a whole number from {} to {}", min, max);
+        self.parse(key, default, &expected, |raw| raw.parse().ok().filter(|v| *v >= min && *v <= max))
+    }
+
+    // whole seconds up to `max`, 0 turns the limit off
+    fn optional_secs(&mut self, key: &str, default: Option<Duration>, max: u64) -> Option<Duration> {
+        let expected = format!("a whole number of seconds up to {}, 0 to disable", max);
+        self.parse(key, default, &expected, |raw| {
+            raw.parse().ok().filter(|secs| *secs <= max).map(|secs| Some(Duration::from_secs(secs)).filter(|d| !d.is_zero()))
+        })
+    }
+
+    // secrets are never echoed back in errors
+    fn required(&mut self, key: &str) -> String {
+        match self.values.get(key) {
+            Some((raw, _)) if !raw.trim().is_empty() => raw.clone(),
+            Some((_, source)) => {
+                self.errors.push(format!("{} ({}): must not be empty", key, source));
+                String::new()
+            }
+            None => {
+                self.errors.push(format!("{}: must be set", key));
+                String::new()
+            }
+        }
+    }
+}
+
+impl AppConfig {
+    // Layers, later ones win: the defaults below, the TOML file (`file`, `CONFIG_FILE` or `./config.toml` if
+    // present), `.env` and the environment.
+    pub fn load(file: Option<&Path>) -> Result<AppConfig, ConfigError> {
+        let mut layers = Layers::default();
+
+        let env_file = env::var("CONFIG_FILE").ok();
+        match file.or(env_file.as_deref().map(Path::new)) {
+            Some(file) => layers.add_file(file),
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => layers.add_file(Path::new(DEFAULT_CONFIG_FILE)),
+            None => {}
+        }
+        layers.add_env();
+
+        let offset_format = format_description!("[offset_hour sign:mandatory]:[offset_minute]");
+        let config = AppConfig {
+            database_url: layers.required("DATABASE_URL"),
+            bind_addr: layers.parse("BIND_ADDR", SocketAddr::from(([127, 0, 0, 1], 3000)), "an address such as 127.0.0.1:3000", |raw| raw.parse().ok()),
+            log_level: layers.parse("LOG_LEVEL", Level::DEBUG, "one of trace, debug, info, warn or error", |raw| raw.parse().ok()),
+            log_utc_offset: layers.parse("LOG_UTC_OFFSET", UtcOffset::from_hms(8, 0, 0).unwrap(), "an offset such as +08:00", |raw| UtcOffset::parse(raw, offset_format).ok()),
+            run_migrations: layers.parse("RUN_MIGRATIONS", false, "true or false", |raw| raw.parse().ok()),
+            startup: StartupConfig {
+                db_deadline: Duration::from_secs(layers.number("STARTUP_DB_DEADLINE_SECS", 30, 0, 24 * 3600)),
+                degraded: layers.parse("STARTUP_DEGRADED", false, "true or false", |raw| raw.parse().ok()),
+            },
+            pool: PoolConfig {
+                size: layers.number("POOL_SIZE", 10, 1, 1000),
+                acquire_timeout: Duration::from_secs(layers.number("POOL_ACQUIRE_TIMEOUT_SECS", 5, 1, 3600)),
+                min_idle: layers.parse("POOL_MIN_IDLE", None, "a whole number", |raw| raw.parse().ok().map(Some)),
+                connection_timeout: Duration::from_secs(layers.number("POOL_CONNECTION_TIMEOUT_SECS", 30, 1, 3600)),
+                idle_timeout: layers.optional_secs("POOL_IDLE_TIMEOUT_SECS", Some(Duration::from_secs(10 * 60)), 30 * 24 * 3600),
+                max_lifetime: layers.optional_secs("POOL_MAX_LIFETIME_SECS", Some(Duration::from_secs(30 * 60)), 30 * 24 * 3600),
+                test_on_check_out: layers.parse("POOL_TEST_ON_CHECK_OUT", true, "true or false", |raw| raw.parse().ok()),
+            },
+            session: SessionConfig {
+                statement_timeout_ms: layers.number("SESSION_STATEMENT_TIMEOUT_MS", 30_000, 0, i32::MAX as u64),
+                application_name: layers.parse("SESSION_APPLICATION_NAME", env!("CARGO_PKG_NAME").to_string(), "at most 63 characters", |raw| {
+                    Some(raw.to_string()).filter(|name| name.len() <= 63)
+                }),
+                search_path: layers.parse("SESSION_SEARCH_PATH", None, "a list of schemas", |raw| Some(Some(raw.to_string()).filter(|path| !path.is_empty()))),
+            },
+            jwt: JwtSettings {
+                secret: layers.required("JWT_SECRET"),
+                expires_in: layers.number("JWT_EXPIRES_SECS", 3600, 1, 365 * 24 * 3600),
+                refresh_expires_in: layers.number("REFRESH_TOKEN_EXPIRES_SECS", 30 * 24 * 3600, 1, 365 * 24 * 3600),
+            },
+            soft_delete_retention_days: layers.number("SOFT_DELETE_RETENTION_DAYS", 30, 0, 3650),
+            purge_interval: Duration::from_secs(layers.number("PURGE_INTERVAL_SECS", 3600, 1, 7 * 24 * 3600)),
+        };
+
+        if PLACEHOLDER_SECRETS.contains(&config.jwt.secret.trim()) {
+            layers.errors.push("JWT_SECRET: is a well-known placeholder, generate one with `openssl rand -base64 32`".to_string());
+        }
+        // r2d2 panics on this
+        if config.pool.min_idle.is_some_and(|min_idle| min_idle > config.pool.size) {
+            layers.errors.push(format!("POOL_MIN_IDLE: must not exceed POOL_SIZE ({})", config.pool.size));
+        }
+
+        if !layers.errors.is_empty() {
+            return Err(ConfigError(layers.errors));
+        }
+        Ok(config)
+    }
+}
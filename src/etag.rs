@@ -0,0 +1,67 @@
+use axum::extract::FromRequestParts;
+use axum::http::header::{IF_MATCH, IF_NONE_MATCH};
+use axum::http::request::Parts;
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use crate::HtyErr;
+
+// ETags of users are their `version` column, e.g. `"3"`.
+pub fn etag(version: i32) -> HeaderValue {
+    HeaderValue::from_str(&format!("\"{}\"", version)).expect("a quoted number is a valid header value")
+}
+
+// Versions listed in `header`, `None` for `*`. Weak tags (`W/"3"`) are only accepted when `weak` is set,
+// tags that aren't ours are skipped and so never match.
+fn versions(headers: &HeaderMap, header: HeaderName, weak: bool) -> Option<Option<Vec<i32>>> {
+    let values: Vec<&str> = headers.get_all(header).iter().filter_map(|v| v.to_str().ok()).collect();
+    if values.is_empty() {
+        return None;
+    }
+
+    let tags: Vec<&str> = values.iter().flat_map(|v| v.split(',')).map(str::trim).collect();
+    if tags.contains(&"*") {
+        return Some(None);
+    }
+
+    Some(Some(tags.iter()
+        .filter(|tag| weak || !tag.starts_with("W/"))
+        .map(|tag| tag.trim_start_matches("W/"))
+        .filter_map(|tag| tag.strip_prefix('"')?.strip_suffix('"')?.parse().ok())
+        .collect()))
+}
+
+// `If-Match` on updates and deletes: `Some(versions)` the row must still be at, `None` when absent or `*`.
+pub struct IfMatch(pub Option<Vec<i32>>);
+
+impl<S> FromRequestParts<S> for IfMatch
+    where
+        S: Send + Sync, {
+    type Rejection = HtyErr;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(IfMatch(versions(&parts.headers, IF_MATCH, false).flatten()))
+    }
+}
+
+// `If-None-Match` on `GET /users/{id}`.
+pub struct IfNoneMatch(Option<Option<Vec<i32>>>);
+
+impl IfNoneMatch {
+    // true when the client's copy is current, i.e. the response is a `304`
+    pub fn matches(&self, version: i32) -> bool {
+        match &self.0 {
+            None => false,
+            Some(None) => true,
+            Some(Some(versions)) => versions.contains(&version),
+        }
+    }
+}
+
+impl<S> FromRequestParts<S> for IfNoneMatch
+    where
+        S: Send + Sync, {
+    type Rejection = HtyErr;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(IfNoneMatch(versions(&parts.headers, IF_NONE_MATCH, true)))
+    }
+}
@@ -0,0 +1,252 @@
+use axum::extract::FromRequestParts;
+use axum::http::request::Parts;
+use chrono::{DateTime, Utc};
+use diesel::pg::Pg;
+use diesel::prelude::*;
+use diesel::sql_types::Text;
+use serde::{Deserialize, Deserializer};
+use serde_json::Value;
+use crate::schema::users;
+use crate::{query_params, HtyErr, HtyErrCode};
+
+// Columns `GET /users` can be sorted by.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SortField {
+    Id,
+    Username,
+    CreatedAt,
+}
+
+impl SortField {
+    pub fn from_name(name: &str) -> Option<SortField> {
+        match name {
+            "id" => Some(SortField::Id),
+            "username" => Some(SortField::Username),
+            "created_at" => Some(SortField::CreatedAt),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sort {
+    pub field: SortField,
+    pub desc: bool,
+}
+
+// `sort=username,-created_at`: comma separated fields, a leading `-` sorts descending
+fn deserialize_sort<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Sort>, D::Error> {
+    let raw = String::deserialize(deserializer)?;
+    raw.split(',')
+        .filter(|key| !key.is_empty())
+        .map(|key| {
+            let (name, desc) = match key.strip_prefix('-') {
+                Some(name) => (name, true),
+                None => (key, false),
+            };
+            SortField::from_name(name)
+                .map(|field| Sort { field, desc })
+                .ok_or_else(|| serde::de::Error::custom(format!("unknown sort field `{}`, expected id, username or created_at", name)))
+        })
+        .collect()
+}
+
+// Filters and sort order of `GET /users`, only the fields below are accepted.
+#[derive(Debug, Default, Deserialize)]
+pub struct UserFilter {
+    #[serde(default, deserialize_with = "deserialize_sort")]
+    pub sort: Vec<Sort>,
+    // RFC 3339, inclusive, `start_from` is the older name
+    #[serde(alias = "start_from")]
+    pub created_after: Option<DateTime<Utc>>,
+    // exclusive
+    pub created_before: Option<DateTime<Utc>>,
+    pub username_prefix: Option<String>,
+    pub id: Option<String>,
+    pub username: Option<String>,
+    // `meta.*` keys, collected from the raw query string by the extractor
+    #[serde(skip)]
+    pub meta: Vec<MetaFilter>,
+    // soft-deleted users are left out unless `rbac::IncludeDeleted` allowed them
+    #[serde(skip)]
+    pub include_deleted: bool,
+}
+
+// Query keys of `GET /users` besides `meta.*`: the fields above and those read by `PageParams` and
+// `rbac::IncludeDeleted`. Anything else is rejected, a misspelled filter would otherwise match every user.
+const FILTER_KEYS: [&str; 7] = ["sort", "created_after", "start_from", "created_before", "username_prefix", "id", "username"];
+const OTHER_KEYS: [&str; 5] = ["page", "page_size", "count", "cursor", "include_deleted"];
+
+// The top level fields of `TypedMeta`, a filter path has to start with one of them.
+const META_ROOTS: [&str; 2] = ["data", "meta"];
+const MAX_META_DEPTH: usize = 4;
+
+// `meta.data.foo=1` matches users whose `meta` contains `{"data": {"foo": "1"}}`,
+// an empty value (`meta.data.foo=`) only requires the path to be present.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetaFilter {
+    pub path: Vec<String>,
+    pub value: String,
+}
+
+impl MetaFilter {
+    fn parse(key: &str, value: &str) -> Result<Option<MetaFilter>, HtyErr> {
+        let Some(path) = key.strip_prefix("meta.") else {
+            return Ok(None);
+        };
+        let path: Vec<String> = path.split('.').map(str::to_string).collect();
+
+        let valid_root = META_ROOTS.contains(&path[0].as_str());
+        if !valid_root || path.len() > MAX_META_DEPTH || path.iter().any(String::is_empty) {
+            return Err(HtyErr {
+                code: HtyErrCode::CommonError,
+                reason: Some(format!("invalid meta filter `{}`, expected meta.data.<key> or meta.meta.<path> up to {} levels", key, MAX_META_DEPTH)),
+            });
+        }
+
+        Ok(Some(MetaFilter {
+            path,
+            value: value.to_string(),
+        }))
+    }
+
+    // `{"data": {"foo": "1"}}` for `meta.data.foo=1`, meta values are always strings
+    fn document(&self) -> Value {
+        self.path.iter().rev().fold(Value::String(self.value.clone()), |inner, key| {
+            let mut object = serde_json::Map::new();
+            object.insert(key.clone(), inner);
+            Value::Object(object)
+        })
+    }
+}
+
+impl UserFilter {
+    pub fn filter(&self, mut query: users::BoxedQuery<'static, Pg>) -> users::BoxedQuery<'static, Pg> {
+        if !self.include_deleted {
+            query = query.filter(users::deleted_at.is_null());
+        }
+        if let Some(created_after) = self.created_after {
+            query = query.filter(users::created_at.ge(created_after));
+        }
+        if let Some(created_before) = self.created_before {
+            query = query.filter(users::created_at.lt(created_before));
+        }
+        if let Some(prefix) = &self.username_prefix {
+            query = query.filter(users::username.like(format!("{}%", escape_like(prefix))));
+        }
+        if let Some(id) = &self.id {
+            query = query.filter(users::id.eq(id.clone()));
+        }
+        if let Some(username) = &self.username {
+            query = query.filter(users::username.eq(username.clone()));
+        }
+        for meta in &self.meta {
+            // `@>` is served by the GIN index on `users.meta`, `#>>` is not
+            query = if meta.value.is_empty() {
+                query.filter(users::meta.retrieve_by_path_as_text(meta.path.clone()).is_not_null())
+            } else {
+                query.filter(users::meta.contains(meta.document()))
+            };
+        }
+        query
+    }
+
+    // newest first unless `sort` is given, `id` breaks ties so offset pages never overlap
+    pub fn sort(&self, mut query: users::BoxedQuery<'static, Pg>) -> users::BoxedQuery<'static, Pg> {
+        if self.sort.is_empty() {
+            query = query.then_order_by(users::created_at.desc());
+        }
+        for sort in &self.sort {
+            query = match (sort.field, sort.desc) {
+                (SortField::Id, false) => query.then_order_by(users::id.asc()),
+                (SortField::Id, true) => query.then_order_by(users::id.desc()),
+                (SortField::Username, false) => query.then_order_by(users::username.asc()),
+                (SortField::Username, true) => query.then_order_by(users::username.desc()),
+                (SortField::CreatedAt, false) => query.then_order_by(users::created_at.asc()),
+                (SortField::CreatedAt, true) => query.then_order_by(users::created_at.desc()),
+            };
+        }
+        query.then_order_by(users::id.asc())
+    }
+}
+
+// `LIKE` treats `%` and `_` as wildcards and `\` as the escape character
+fn escape_like(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")
+}
+
+impl<S> FromRequestParts<S> for UserFilter
+    where
+        S: Send + Sync, {
+    type Rejection = HtyErr;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let mut filter: UserFilter = query_params(parts)?;
+
+        let pairs: Vec<(String, String)> = query_params(parts)?;
+        for (key, value) in pairs {
+            if let Some(meta) = MetaFilter::parse(&key, &value)? {
+                filter.meta.push(meta);
+            } else if !FILTER_KEYS.contains(&key.as_str()) && !OTHER_KEYS.contains(&key.as_str()) {
+                return Err(HtyErr {
+                    code: HtyErrCode::CommonError,
+                    reason: Some(format!("unknown query parameter `{}`, expected one of {} or meta.<path>", key, FILTER_KEYS.join(", "))),
+                });
+            }
+        }
+
+        Ok(filter)
+    }
+}
+
+// pg_trgm, see `migrations/*_add_users_username_trgm_index`
+define_sql_function!(fn similarity(a: Text, b: Text) -> Float4);
+define_sql_function!(fn word_similarity(a: Text, b: Text) -> Float4);
+diesel::infix_operator!(Similar, " % ", backend: Pg);
+diesel::infix_operator!(WordSimilar, " <% ", backend: Pg);
+
+const MAX_SEARCH_LEN: usize = 100;
+
+// `GET /users/search?q=`: partial, typo tolerant username lookup.
+#[derive(Debug, Deserialize)]
+pub struct UserSearch {
+    pub q: String,
+}
+
+impl UserSearch {
+    // substring matches, names containing a word close to `q` (`<%`) and names close to `q` as a whole (`%`),
+    // best matches first
+    pub fn search(&self, query: users::BoxedQuery<'static, Pg>) -> users::BoxedQuery<'static, Pg> {
+        let q = self.q.clone();
+        let substring = format!("%{}%", escape_like(&q));
+
+        query
+            .filter(users::deleted_at.is_null())
+            .filter(users::username.ilike(substring)
+                .or(WordSimilar::new(q.clone().into_sql::<Text>(), users::username))
+                .or(Similar::new(users::username, q.clone().into_sql::<Text>())))
+            .order(word_similarity(q.clone(), users::username).desc())
+            .then_order_by(similarity(q, users::username).desc())
+            .then_order_by(users::id.asc())
+    }
+}
+
+impl<S> FromRequestParts<S> for UserSearch
+    where
+        S: Send + Sync, {
+    type Rejection = HtyErr;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let search: UserSearch = query_params(parts)?;
+        let q = search.q.trim();
+
+        if q.is_empty() || q.chars().count() > MAX_SEARCH_LEN {
+            return Err(HtyErr {
+                code: HtyErrCode::CommonError,
+                reason: Some(format!("`q` must be 1 to {} characters", MAX_SEARCH_LEN)),
+            });
+        }
+
+        Ok(UserSearch { q: q.to_string() })
+    }
+}
@@ -0,0 +1,37 @@
+use axum::extract::State;
+use axum::Json;
+use diesel::sql_query;
+use diesel::RunQueryDsl;
+use serde::{Deserialize, Serialize};
+use crate::pool::PoolStats;
+use crate::{DbConn, HtyErr, MyDbState, MyResponse};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Health {
+    // `ok`, or `degraded` while the database is unavailable, see `startup::spawn_recovery`
+    pub status: String,
+    pub pool: PoolStats,
+}
+
+fn health(status: &str, db: &MyDbState) -> Json<MyResponse<Health>> {
+    Json(MyResponse {
+        r: true,
+        d: Some(Health { status: status.to_string(), pool: db.pool_stats() }),
+        e: None,
+    })
+}
+
+// The process is up and answering, also in degraded mode.
+pub async fn live(State(db): State<MyDbState>) -> Json<MyResponse<Health>> {
+    health(if db.is_available() { "ok" } else { "degraded" }, &db)
+}
+
+// The database answers a query, 503 otherwise.
+pub async fn ready(State(db): State<MyDbState>, conn: DbConn) -> Result<Json<MyResponse<Health>>, HtyErr> {
+    conn.interact(|conn| {
+        sql_query("select 1").execute(conn)?;
+        Ok(())
+    }).await?;
+
+    Ok(health("ok", &db))
+}
@@ -3,41 +3,70 @@ extern crate diesel;
 
 mod schema;
 mod pagination;
+mod auth;
+mod rbac;
+mod filter;
+mod bulk;
+mod purge;
+mod etag;
+mod audit;
+mod transaction;
+mod migrate;
+mod cli;
+mod config;
+mod pool;
+mod startup;
+mod health;
 
 use std::collections::HashMap;
 use crate::schema::{users};
-use std::{env, fmt};
+use std::fmt;
 use std::fmt::{Debug, Formatter};
 use std::future::Future;
 use std::io::{stdout, Write};
-use axum::{routing::{get, post}, http::StatusCode, response::IntoResponse, Json, Router};
-use serde::{Deserialize, Serialize};
-use std::net::SocketAddr;
-use std::ops::{Deref, DerefMut};
+use axum::{routing::{delete, get, post, put}, http::StatusCode, response::{IntoResponse, Response}, Json, Router};
+use axum::http::HeaderValue;
+use axum::middleware::{from_extractor_with_state, from_fn_with_state, map_response};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::ops::Deref;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
-use anyhow::anyhow;
-use axum::extract::{FromRef, FromRequestParts, Query, State};
-use axum::http::header::HOST;
-use diesel::{insert_into, PgConnection, QueryDsl, RunQueryDsl, sql_query, ExpressionMethods, OptionalExtension};
+use axum::extract::{FromRef, FromRequest, FromRequestParts, Query, Request, State};
+use axum::http::header::{ETAG, HOST};
+use diesel::{insert_into, Connection, PgConnection, QueryDsl, RunQueryDsl, sql_query, ExpressionMethods, OptionalExtension};
+use diesel::dsl::{now, sql};
+use diesel::{BoxableExpression, IntoSql};
 use diesel::r2d2::{ConnectionManager, Pool, PooledConnection};
-use diesel::sql_types::Jsonb;
+use diesel::sql_types::{Bool, Jsonb, Nullable, Varchar};
+use diesel::expression::SqlLiteral;
+use clap::Parser;
 use dotenv::dotenv;
 use uuid::Uuid;
-use axum::extract::{Path};
+use axum::extract::Path;
+use axum::extract::rejection::PathRejection;
 use axum::http::request::Parts;
-use chrono::{Local, NaiveDateTime};
+use chrono::{DateTime, NaiveDateTime, SecondsFormat, Utc};
 use diesel::pg::{Pg, PgValue};
 use diesel::serialize::IsNull;
 use tokio::time::sleep;
 use tokio::task;
 use diesel::sql_types::BigInt;
-use tracing::{debug, error, Level};
+use tracing::{debug, error, warn, Level};
 use serde::de::DeserializeOwned;
+use serde_json::Value;
 use time::macros::format_description;
-use time::UtcOffset;
 use tokio::net::TcpListener;
 use tracing_subscriber::fmt::time::OffsetTime;
+use crate::audit::{Actor, AuditAction};
+use crate::cli::{Cli, Command, ServeArgs};
+use crate::config::AppConfig;
+use crate::auth::{AuthUser, JwtConfig, MyJwtState, RefreshToken};
+use crate::etag::{etag, IfMatch, IfNoneMatch};
+use crate::filter::{UserFilter, UserSearch};
+use crate::pagination::{Cursor, Keyset, KeysetPage, Page, PageParams};
+use crate::rbac::{IncludeDeleted, Permission, PermissionGuard};
+use crate::transaction::Isolation;
 
 
 pub type PgPool = Pool<PgConnMgr>;
@@ -46,29 +75,55 @@ pub type PooledPgConn = PooledConnection<PgConnMgr>;
 
 pub struct DbConn(pub PooledPgConn);
 
-pub fn pool(db_url: &String) -> PgPool {
-    let manager = PgConnMgr::new(db_url);
-    let max_size = env::var("POOL_SIZE")
-        .expect("POOL_SIZE must be set")
-        .parse::<u32>()
-        .unwrap();
+// Connections are opened in the background, so a database that is down doesn't fail the build, see `startup`.
+pub fn pool(config: &AppConfig, events: pool::PoolEvents) -> PgPool {
+    let manager = PgConnMgr::new(&config.database_url);
 
     Pool::builder()
-        .max_size(max_size)
-        .build(manager)
-        .expect("DB Connection Pool Build Error!")
+        .max_size(config.pool.size)
+        .min_idle(config.pool.min_idle)
+        .connection_timeout(config.pool.connection_timeout)
+        .idle_timeout(config.pool.idle_timeout)
+        .max_lifetime(config.pool.max_lifetime)
+        .test_on_check_out(config.pool.test_on_check_out)
+        .connection_customizer(Box::new(pool::ConnectionSetup(config.session.clone())))
+        .event_handler(Box::new(events))
+        .error_handler(Box::new(pool::ConnectionErrors))
+        .build_unchecked(manager)
 }
 
 
-pub fn get_conn(pool: &PgPool) -> PooledPgConn {
-    match pool.get() {
-        Ok(conn) => conn,
-        Err(_) => panic!("DB Connection Error!"),
-    }
+pub fn get_conn(pool: &PgPool) -> Result<PooledPgConn, HtyErr> {
+    Ok(pool.get()?)
 }
 
 pub struct DbState {
     pool: PgPool,
+    // how long a request waits for a pooled connection before giving up with 503
+    acquire_timeout: Duration,
+    // false while the server runs in degraded mode, see `startup::spawn_recovery`
+    available: AtomicBool,
+    events: pool::PoolEvents,
+}
+
+impl DbState {
+    pub fn new(config: &AppConfig) -> Self {
+        let events = pool::PoolEvents::default();
+        DbState {
+            pool: pool(config, events.clone()),
+            acquire_timeout: config.pool.acquire_timeout,
+            available: AtomicBool::new(true),
+            events,
+        }
+    }
+
+    pub fn is_available(&self) -> bool {
+        self.available.load(Ordering::Relaxed)
+    }
+
+    pub fn pool_stats(&self) -> pool::PoolStats {
+        self.events.stats(self.pool.state())
+    }
 }
 
 
@@ -86,14 +141,18 @@ pub struct MyResponse<T> {
 }
 
 
-fn internal_error<E>(err: E) -> (StatusCode, String)
+fn internal_error<E>(err: E) -> HtyErr
     where
         E: std::error::Error,
 {
-    (StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+    HtyErr {
+        code: HtyErrCode::InternalErr,
+        reason: Some(err.to_string()),
+    }
 }
 
 
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct HostHeader(pub String);
 
@@ -106,32 +165,139 @@ impl Deref for HostHeader {
 
 impl<B> FromRequestParts<B> for HostHeader where
     B: Send + Sync, {
-    type Rejection = (StatusCode, String);
+    type Rejection = HtyErr;
 
     async fn from_request_parts(parts: &mut Parts, _state: &B) -> Result<Self, Self::Rejection> {
-        let headers = parts.headers.clone();
-        Ok(Self(headers[HOST].to_str().map_err(internal_error)?.to_string()))
+        let host = parts.headers.get(HOST).ok_or(HtyErr {
+            code: HtyErrCode::NullErr,
+            reason: Some("missing Host header".to_string()),
+        })?;
+        Ok(Self(host.to_str().map_err(internal_error)?.to_string()))
+    }
+}
+
+// Deserializes the query string for extractors that validate it further, malformed values are a 400.
+pub fn query_params<T: DeserializeOwned>(parts: &Parts) -> Result<T, HtyErr> {
+    let Query(params) = Query::<T>::try_from_uri(&parts.uri).map_err(|e| HtyErr {
+        code: HtyErrCode::CommonError,
+        reason: Some(e.body_text()),
+    })?;
+    Ok(params)
+}
+
+
+// `Json` and `Path` whose rejections are answered in the `MyResponse` envelope like every other error, rather than
+// as axum's plain text.
+pub struct JsonBody<T>(pub T);
+
+impl<S, T> FromRequest<S> for JsonBody<T>
+    where
+        T: DeserializeOwned,
+        S: Send + Sync, {
+    type Rejection = HtyErr;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let Json(payload) = Json::<T>::from_request(req, state).await.map_err(|e| HtyErr {
+            code: HtyErrCode::CommonError,
+            reason: Some(e.body_text()),
+        })?;
+        Ok(JsonBody(payload))
+    }
+}
+
+pub struct PathParam<T>(pub T);
+
+impl<S, T> FromRequestParts<S> for PathParam<T>
+    where
+        T: DeserializeOwned + Send,
+        S: Send + Sync, {
+    type Rejection = HtyErr;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Path(params) = Path::<T>::from_request_parts(parts, state).await.map_err(|e| HtyErr {
+            // a route without the parameter is a bug, not a bad request
+            code: match e {
+                PathRejection::FailedToDeserializePathParams(_) => HtyErrCode::CommonError,
+                _ => HtyErrCode::InternalErr,
+            },
+            reason: Some(e.body_text()),
+        })?;
+        Ok(PathParam(params))
     }
 }
 
 
 type MyDbState = Arc<DbState>;
 
+type MyConfigState = Arc<AppConfig>;
+
+#[derive(Clone)]
+pub struct AppState {
+    db: MyDbState,
+    jwt: MyJwtState,
+    config: MyConfigState,
+}
+
+impl FromRef<AppState> for MyDbState {
+    fn from_ref(state: &AppState) -> Self {
+        state.db.clone()
+    }
+}
+
+impl FromRef<AppState> for MyJwtState {
+    fn from_ref(state: &AppState) -> Self {
+        state.jwt.clone()
+    }
+}
+
+impl FromRef<AppState> for MyConfigState {
+    fn from_ref(state: &AppState) -> Self {
+        state.config.clone()
+    }
+}
+
 impl<B> FromRequestParts<B> for DbConn
     where
         MyDbState: FromRef<B>,
         B: Send + Sync, {
-    type Rejection = (StatusCode, String);
+    type Rejection = HtyErr;
 
     async fn from_request_parts(_parts: &mut Parts, state: &B) -> Result<Self, Self::Rejection> {
         // let Extension(db_pool) = Extension::<Arc<DbState>>::from_request_parts(req).await
         //     .map_err(internal_error)?;
-        let db_pool = MyDbState::from_ref(state);
+        DbConn::acquire(MyDbState::from_ref(state)).await
+    }
+}
 
-        let conn = db_pool.pool.get().map_err(internal_error)?;
+impl DbConn {
+    // r2d2 blocks while waiting for a free connection, so the wait happens on the blocking pool
+    pub async fn acquire(db_state: MyDbState) -> Result<Self, HtyErr> {
+        // fail fast rather than have every request wait `acquire_timeout` for a database that is known to be down
+        if !db_state.is_available() {
+            return Err(HtyErr {
+                code: HtyErrCode::DbUnavailableErr,
+                reason: Some("database unavailable, the server is running in degraded mode".to_string()),
+            });
+        }
+
+        let conn = task::spawn_blocking(move || db_state.pool.get_timeout(db_state.acquire_timeout))
+            .await
+            .map_err(internal_error)??;
 
         Ok(Self(conn))
     }
+
+    // diesel is synchronous: run `f` on tokio's blocking pool instead of the async worker threads.
+    pub async fn interact<F, R>(self, f: F) -> Result<R, HtyErr>
+        where
+            F: FnOnce(&mut PgConnection) -> Result<R, HtyErr> + Send + 'static,
+            R: Send + 'static,
+    {
+        let mut conn = extract_conn(self);
+        task::spawn_blocking(move || f(&mut conn))
+            .await
+            .map_err(internal_error)?
+    }
 }
 
 impl Deref for DbConn {
@@ -146,9 +312,9 @@ impl Deref for DbConn {
 // https://github.com/tokio-rs/axum/discussions/930
 // https://docs.rs/axum/latest/axum/extract/index.html#applying-multiple-extractors
 // https://docs.rs/axum/latest/axum/struct.Extension.html
-async fn req_conn(State(db_pool): State<Arc<DbState>>) -> String {
-    let _conn = db_pool.pool.get().unwrap();
-    "OK".to_string()
+async fn req_conn(State(db_pool): State<Arc<DbState>>) -> Result<String, HtyErr> {
+    let _conn = DbConn::acquire(db_pool).await?;
+    Ok("OK".to_string())
 }
 
 async fn nested_async() -> String {
@@ -167,10 +333,12 @@ pub fn extract_conn(conn: DbConn) -> PooledPgConn {
     conn.0
 }
 
-async fn play_with_raw_query(conn: DbConn) -> String {
-    let r = sql_query("select count(1) as result;").get_result::<MyQuery>(extract_conn(conn).deref_mut()).unwrap().result;
+async fn play_with_raw_query(conn: DbConn) -> Result<String, HtyErr> {
+    let r = conn.interact(|conn| {
+        Ok(sql_query("select count(1) as result;").get_result::<MyQuery>(conn)?.result)
+    }).await?;
     println!("{}", r);
-    "OK".to_string()
+    Ok("OK".to_string())
 }
 
 async fn inner_async() {
@@ -178,7 +346,7 @@ async fn inner_async() {
     println!("INNER");
 }
 
-async fn path(Path(id): Path<String>) -> String {
+async fn path(PathParam(id): PathParam<String>) -> String {
     if id.is_empty() {
         "<NONE>".to_string()
     } else {
@@ -187,7 +355,7 @@ async fn path(Path(id): Path<String>) -> String {
 }
 
 
-async fn path2(Path(path_id): Path<String>) -> String {
+async fn path2(PathParam(path_id): PathParam<String>) -> String {
     if path_id.is_empty() {
         "<NONE>".to_string()
     } else {
@@ -195,7 +363,7 @@ async fn path2(Path(path_id): Path<String>) -> String {
     }
 }
 
-async fn post_with_path(Path(path_id): Path<String>) -> String {
+async fn post_with_path(PathParam(path_id): PathParam<String>) -> String {
     path_id
 }
 
@@ -228,11 +396,12 @@ async fn my_resp() -> Json<MyResponse<String>> {
 }
 
 
-async fn mix(Path(id): Path<String>,
+async fn mix(PathParam(id): PathParam<String>,
              host: HostHeader,
              conn: DbConn,
-             Json(payload): Json<ReqUser>) -> Json<MyResponse<String>> {
-    let resp_str = format!("{:?} / {:?} / {:?} / {:?}", id, host, payload, all_users(extract_conn(conn).deref_mut()));
+             JsonBody(payload): JsonBody<ReqUser>) -> Result<Json<MyResponse<String>>, HtyErr> {
+    let all = conn.interact(all_users).await?;
+    let resp_str = format!("{:?} / {:?} / {:?} / {:?}", id, host, payload, all);
 
     let resp = MyResponse {
         r: true,
@@ -240,53 +409,106 @@ async fn mix(Path(id): Path<String>,
         e: None,
     };
 
-    Json(resp)
+    Ok(Json(resp))
 }
 
-async fn find_user_by_id(conn: DbConn, Path(id): Path<String>) -> Json<MyResponse<TypedUser<ReqWxMessageData4KeywordTemplate>>> {
-    let typed_user = TypedUser::<ReqWxMessageData4KeywordTemplate>::find_typed_user_by_id(&id, &mut extract_conn(conn)).unwrap();
+async fn find_user_by_id(
+    PathParam(id): PathParam<String>,
+    IncludeDeleted(include_deleted): IncludeDeleted,
+    conn: DbConn,
+    if_none_match: IfNoneMatch) -> Result<Response, HtyErr> {
+    let typed_user = conn.interact(move |conn| TypedUser::<ReqWxMessageData4KeywordTemplate>::find_typed_user_by_id(&id, include_deleted, conn)).await?;
+    let version = typed_user.version;
+    if if_none_match.matches(version) {
+        return Ok((StatusCode::NOT_MODIFIED, [(ETAG, etag(version))]).into_response());
+    }
+
     let resp = MyResponse {
         r: true,
         d: Some(typed_user),
         e: None,
     };
 
-    Json(resp)
+    Ok(([(ETAG, etag(version))], Json(resp)).into_response())
 }
 
 // https://stackoverflow.com/questions/61179070/rust-chrono-parse-date-string-parseerrornotenough-and-parseerrortooshort
-async fn get_users_by_page(Query(params): Query<HashMap<String, String>>, conn: DbConn) -> Json<MyResponse<(Vec<TypedUser<ReqWxMessageData4KeywordTemplate>>, i64, i64)>> {
-    // let start_date = params.get("start_from").unwrap().as_str();
-    // println!("start_date -> {}", start_date);
+async fn get_users_by_page(
+    page_params: PageParams,
+    mut filter: UserFilter,
+    IncludeDeleted(include_deleted): IncludeDeleted,
+    conn: DbConn) -> Result<Json<MyResponse<UsersPage>>, HtyErr> {
+    filter.include_deleted = include_deleted;
+
+    // `?cursor=` (empty) starts keyset pagination, later pages pass back `next_cursor` / `prev_cursor`
+    if let Some(cursor) = &page_params.cursor {
+        // keyset pages are always ordered by `(created_at, id)`
+        if !filter.sort.is_empty() {
+            return Err(HtyErr {
+                code: HtyErrCode::CommonError,
+                reason: Some("`sort` can't be combined with `cursor`".to_string()),
+            });
+        }
+        let some_cursor = if cursor.is_empty() {
+            None
+        } else {
+            Some(Cursor::decode(cursor).ok_or(HtyErr {
+                code: HtyErrCode::CommonError,
+                reason: Some("invalid `cursor`".to_string()),
+            })?)
+        };
+        let page_size = page_params.page_size;
+
+        let r = conn.interact(move |conn| keyset_paginate_users(some_cursor, page_size, &filter, conn)).await?;
+
+        let resp = MyResponse {
+            r: true,
+            d: Some(UsersPage::Keyset(r)),
+            e: None,
+        };
+        return Ok(Json(resp));
+    }
 
-    let some_page = params.get("page");
-    let some_page_size = params.get("page_size").clone();
+    debug!("page: {:?}, page_size: {:?}", &page_params.page, &page_params.page_size);
 
-    let param_some_page = if some_page.is_some() { Some(some_page.unwrap().parse::<i64>().unwrap()) } else { None };
-    let param_some_page_size = if some_page_size.is_some() { Some(some_page_size.unwrap().parse::<i64>().unwrap()) } else { None };
+    let r = conn.interact(move |conn| paginate_users(&page_params, &filter, conn)).await;
 
-    let page_params = PageParams {
-        page: param_some_page,
-        page_size: param_some_page_size,
-        start_from: None,
-        // start_from: Some(NaiveDateTime::parse_from_str(start_date, "%Y-%m-%d %H:%M:%S").unwrap()),
-    };
+    debug!("get_users_by_page -> {:?}", r);
 
+    let resp = MyResponse {
+        r: true,
+        d: Some(UsersPage::Offset(r?)),
+        e: None,
+    };
 
-    println!("page: {:?}, page_size: {:?}", &page_params.page, &page_params.page_size);
+    Ok(Json(resp))
+}
 
-    let r = paginate_users(&page_params, &mut extract_conn(conn));
+// `GET /users/search?q=`, ranked by trigram similarity
+async fn search_users_by_name(page_params: PageParams, search: UserSearch, conn: DbConn) -> Result<Json<MyResponse<Page<TypedUser<ReqWxMessageData4KeywordTemplate>>>>, HtyErr> {
+    if page_params.cursor.is_some() {
+        return Err(HtyErr {
+            code: HtyErrCode::CommonError,
+            reason: Some("search results can't be paginated with `cursor`".to_string()),
+        });
+    }
 
-    println!("get_users_by_page -> {:?}", r);
+    let r = conn.interact(move |conn| search_users(&page_params, &search, conn)).await?;
 
     let resp = MyResponse {
         r: true,
-        d: Some(r.unwrap()),
-        // d: None,
+        d: Some(r),
         e: None,
     };
 
-    Json(resp)
+    Ok(Json(resp))
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum UsersPage {
+    Offset(Page<TypedUser<ReqWxMessageData4KeywordTemplate>>),
+    Keyset(KeysetPage<TypedUser<ReqWxMessageData4KeywordTemplate>>),
 }
 
 
@@ -332,13 +554,17 @@ async fn query(Query(params): Query<HashMap<String, String>>) -> Json<MyResponse
 
 
 fn db_create_typed_user<T: Debug + Serialize + DeserializeOwned + Clone,
-    W: Clone + Debug + Serialize + DeserializeOwned + 'static>(db_conn: DbConn, in_user: &TypedUser<T>) -> TypedUser<W> {
+    W: Clone + Debug + Serialize + DeserializeOwned + 'static>(conn: &mut PgConnection, in_user: &NewTypedUser<T>, actor: &Actor) -> Result<TypedUser<W>, HtyErr> {
     use crate::schema::users::dsl::*;
 
-    insert_into(users)
-        .values(in_user.clone())
-        .get_result::<TypedUser<W>>(extract_conn(db_conn).deref_mut())
-        .unwrap()
+    conn.transaction(|conn| {
+        let created_user = insert_into(users)
+            .values(in_user.clone())
+            .get_result::<TypedUser<W>>(conn)?;
+
+        audit::db_record(conn, actor, AuditAction::Create, &created_user.id, None, Some(&created_user))?;
+        Ok(created_user)
+    })
 }
 
 
@@ -352,16 +578,99 @@ fn _db_create_user(conn: &mut PgConnection, in_user: &User) -> User {
 }
 
 
-async fn delete_user_by_id(conn: DbConn, Path(id): Path<String>) -> impl IntoResponse {
-    let to_delete_user = TypedUser::<String>::db_delete_typed_user::<String>(&mut extract_conn(conn), &id).unwrap();
+async fn delete_user_by_id(conn: DbConn, PathParam(id): PathParam<String>, IfMatch(expected): IfMatch, actor: Actor) -> Result<impl IntoResponse, HtyErr> {
+    let to_delete_user = conn.transaction(Isolation::ReadCommitted, move |conn| {
+        TypedUser::<ReqWxMessageData4KeywordTemplate>::db_delete_typed_user::<ReqWxMessageData4KeywordTemplate>(conn, &id, &expected, &actor)
+    }).await?;
+
+    Ok((StatusCode::OK, [(ETAG, etag(to_delete_user.version))], Json(to_delete_user)))
+}
+
+// `POST /users/{id}/restore`: undoes a soft delete that hasn't been purged yet.
+async fn restore_user(conn: DbConn, PathParam(id): PathParam<String>, actor: Actor) -> Result<impl IntoResponse, HtyErr> {
+    let restored_user = conn.interact(move |conn| {
+        TypedUser::<ReqWxMessageData4KeywordTemplate>::db_restore_typed_user(conn, &id, &actor)
+    }).await?;
+    let version = restored_user.version;
+
+    let resp = MyResponse {
+        r: true,
+        d: Some(restored_user),
+        e: None,
+    };
+
+    Ok(([(ETAG, etag(version))], Json(resp)))
+}
+
+
+// `PUT /users/{id}`: replaces `username` and `meta`, a missing `meta` clears the column.
+async fn replace_user(
+    conn: DbConn,
+    PathParam(id): PathParam<String>,
+    IfMatch(expected): IfMatch,
+    actor: Actor,
+    JsonBody(payload): JsonBody<ReqTypedUser<ReqWxMessageData4KeywordTemplate>>) -> Result<impl IntoResponse, HtyErr> {
+    if payload.id.as_ref().is_some_and(|payload_id| *payload_id != id) {
+        return Err(HtyErr {
+            code: HtyErrCode::NotEqualErr,
+            reason: Some("id in body does not match path".to_string()),
+        });
+    }
+
+    let in_username = payload.username.ok_or(HtyErr {
+        code: HtyErrCode::NullErr,
+        reason: Some("username is required".to_string()),
+    })?;
+
+    let updated_user = conn.interact(move |conn| {
+        TypedUser::<ReqWxMessageData4KeywordTemplate>::db_update_typed_user(conn, &id, &in_username, &payload.meta, &expected, &actor)
+    }).await?;
+    let version = updated_user.version;
 
-    (StatusCode::OK, Json(to_delete_user))
+    let resp = MyResponse {
+        r: true,
+        d: Some(updated_user),
+        e: None,
+    };
+
+    Ok(([(ETAG, etag(version))], Json(resp)))
+}
+
+
+// `PATCH /users/{id}`: updates `username` if present and JSON-merges `meta` (RFC 7396).
+async fn patch_user(
+    conn: DbConn,
+    PathParam(id): PathParam<String>,
+    IfMatch(expected): IfMatch,
+    actor: Actor,
+    JsonBody(payload): JsonBody<ReqPatchUser>) -> Result<impl IntoResponse, HtyErr> {
+    // a concurrent change of the same user fails the snapshot and the merge is redone on the new row
+    let patched_user = conn.transaction(Isolation::RepeatableRead, move |conn| {
+        TypedUser::<ReqWxMessageData4KeywordTemplate>::db_patch_typed_user(conn, &id, &payload, &expected, &actor)
+    }).await?;
+    let version = patched_user.version;
+
+    let resp = MyResponse {
+        r: true,
+        d: Some(patched_user),
+        e: None,
+    };
+
+    Ok(([(ETAG, etag(version))], Json(resp)))
+}
+
+
+// old RPC-style routes are kept for existing clients, flagged with a `Deprecation` header
+async fn deprecated_alias(mut res: Response) -> Response {
+    res.headers_mut().insert("deprecation", HeaderValue::from_static("true"));
+    res
 }
 
 
 async fn create_with_typed_user(
     conn: DbConn,
-    Json(payload): Json<ReqTypedUser<ReqWxMessageData4KeywordTemplate>>) -> impl IntoResponse {
+    actor: Actor,
+    JsonBody(payload): JsonBody<ReqTypedUser<ReqWxMessageData4KeywordTemplate>>) -> Result<impl IntoResponse, HtyErr> {
     let mut data: HashMap<String, String> = HashMap::new();
 
     data.insert("foo".to_string(), "1".to_string());
@@ -373,30 +682,32 @@ async fn create_with_typed_user(
     };
 
     // insert your application logic here
-    let in_user = TypedUser {
+    let in_user = NewTypedUser {
         id: uuid(),
-        username: payload.username.unwrap(),
-        created_at: Some(Local::now().naive_local()),
+        username: payload.username.ok_or(HtyErr {
+            code: HtyErrCode::NullErr,
+            reason: Some("username is required".to_string()),
+        })?,
         meta: Some(meta),
+        password_hash: None,
     };
 
-    let created_user = db_create_typed_user::<ReqWxMessageData4KeywordTemplate, String>(conn, &in_user);
+    let created_user = conn.interact(move |conn| db_create_typed_user::<ReqWxMessageData4KeywordTemplate, String>(conn, &in_user, &actor)).await?;
 
     let out_user = ReqTypedUser {
         id: Some(created_user.id),
         username: Some(created_user.username),
-        created_at: Some(created_user.created_at.unwrap().to_string()),
+        created_at: Some(rfc3339(&created_user.created_at)),
+        updated_at: Some(rfc3339(&created_user.updated_at)),
         meta: created_user.meta.clone(),
     };
 
-    (StatusCode::CREATED, Json(out_user))
+    Ok((StatusCode::CREATED, Json(out_user)))
 }
 
 
-async fn create_user(
-    conn: DbConn,
-    Json(payload): Json<ReqUser>,
-) -> impl IntoResponse {
+// The row `POST /users` and `POST /users/bulk` insert for a request, without the password hash.
+fn new_typed_user(payload: &ReqUser) -> Result<NewTypedUser<ReqWxMessageData4KeywordTemplate>, HtyErr> {
     let mut data: HashMap<String, String> = HashMap::new();
 
     data.insert("foo".to_string(), "1".to_string());
@@ -410,25 +721,58 @@ async fn create_user(
         data: Some(data),
     };
 
-
     // insert your application logic here
-    let in_user = TypedUser {
+    Ok(NewTypedUser {
         id: uuid(),
-        username: payload.username.unwrap(),
-        created_at: Some(Local::now().naive_local()),
+        username: payload.username.clone().ok_or(HtyErr {
+            code: HtyErrCode::NullErr,
+            reason: Some("username is required".to_string()),
+        })?,
         meta: Some(meta),
-    };
+        password_hash: None,
+    })
+}
 
-    let created_user = db_create_typed_user::<ReqWxMessageData4KeywordTemplate, ReqWxMessageData4KeywordTemplate>(conn, &in_user);
+async fn create_user(
+    conn: DbConn,
+    actor: Actor,
+    JsonBody(payload): JsonBody<ReqUser>,
+) -> Result<impl IntoResponse, HtyErr> {
+    let mut in_user = new_typed_user(&payload)?;
+
+    let password = payload.password;
+    let created_user = conn.interact(move |conn| {
+        // argon2 is CPU bound, so hash on the blocking pool as well
+        in_user.password_hash = password.as_deref().map(auth::hash_password).transpose()?;
+        db_create_typed_user::<ReqWxMessageData4KeywordTemplate, ReqWxMessageData4KeywordTemplate>(conn, &in_user, &actor)
+    }).await?;
 
     let out_user = ReqTypedUser {
         id: Some(created_user.id),
         username: Some(created_user.username),
-        created_at: Some(created_user.created_at.unwrap().to_string()),
+        created_at: Some(rfc3339(&created_user.created_at)),
+        updated_at: Some(rfc3339(&created_user.updated_at)),
         meta: created_user.meta.clone(),
     };
 
-    (StatusCode::CREATED, Json(out_user))
+    Ok((StatusCode::CREATED, Json(out_user)))
+}
+
+
+// `POST /register`: public sign-up, unlike `POST /users` a password is mandatory.
+async fn register(
+    conn: DbConn,
+    actor: Actor,
+    JsonBody(payload): JsonBody<ReqUser>,
+) -> Result<impl IntoResponse, HtyErr> {
+    if payload.password.is_none() {
+        return Err(HtyErr {
+            code: HtyErrCode::NullErr,
+            reason: Some("password is required".to_string()),
+        });
+    }
+
+    create_user(conn, actor, JsonBody(payload)).await
 }
 
 
@@ -469,6 +813,12 @@ pub enum HtyErrCode {
     NotEqualErr,
     AuthenticationFailed,
     ConflictErr,
+    ConstraintErr,
+    DbUnavailableErr,
+    ForbiddenErr,
+    PreconditionFailedErr,
+    // lost against a concurrent transaction, see `transaction::db_transaction`
+    SerializationErr,
 }
 
 impl fmt::Display for HtyErrCode {
@@ -483,6 +833,88 @@ impl PartialEq for HtyErrCode {
     }
 }
 
+impl HtyErrCode {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            HtyErrCode::NotFoundErr => StatusCode::NOT_FOUND,
+            HtyErrCode::ConflictErr | HtyErrCode::SerializationErr => StatusCode::CONFLICT,
+            HtyErrCode::ConstraintErr => StatusCode::UNPROCESSABLE_ENTITY,
+            HtyErrCode::DbUnavailableErr => StatusCode::SERVICE_UNAVAILABLE,
+            HtyErrCode::AuthenticationFailed | HtyErrCode::JwtErr => StatusCode::UNAUTHORIZED,
+            HtyErrCode::ForbiddenErr => StatusCode::FORBIDDEN,
+            HtyErrCode::PreconditionFailedErr => StatusCode::PRECONDITION_FAILED,
+            HtyErrCode::CommonError | HtyErrCode::NullErr | HtyErrCode::NotEqualErr => StatusCode::BAD_REQUEST,
+            HtyErrCode::WebErr | HtyErrCode::WxErr => StatusCode::BAD_GATEWAY,
+            HtyErrCode::DbErr | HtyErrCode::InternalErr => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+// https://docs.rs/axum/latest/axum/error_handling/index.html
+impl IntoResponse for HtyErr {
+    fn into_response(self) -> Response {
+        let status = self.code.status_code();
+        if status.is_server_error() {
+            error!("{} -> {:?}", status, self);
+        } else {
+            debug!("{} -> {:?}", status, self);
+        }
+
+        let resp: MyResponse<()> = MyResponse {
+            r: false,
+            d: None,
+            e: Some(self.to_string()),
+        };
+
+        (status, Json(resp)).into_response()
+    }
+}
+
+impl From<diesel::result::Error> for HtyErr {
+    fn from(err: diesel::result::Error) -> Self {
+        use diesel::result::{DatabaseErrorKind, Error};
+
+        let code = match &err {
+            Error::NotFound => HtyErrCode::NotFoundErr,
+            Error::DatabaseError(kind, info) => match kind {
+                DatabaseErrorKind::UniqueViolation => {
+                    // `users_username_uindex` is the only user-facing unique index, see migrations
+                    if info.constraint_name() == Some("users_username_uindex") {
+                        return HtyErr {
+                            code: HtyErrCode::ConflictErr,
+                            reason: Some("username already exists".to_string()),
+                        };
+                    }
+                    HtyErrCode::ConflictErr
+                }
+                DatabaseErrorKind::SerializationFailure => HtyErrCode::SerializationErr,
+                DatabaseErrorKind::ForeignKeyViolation
+                | DatabaseErrorKind::CheckViolation
+                | DatabaseErrorKind::NotNullViolation => HtyErrCode::ConstraintErr,
+                DatabaseErrorKind::ClosedConnection
+                | DatabaseErrorKind::UnableToSendCommand => HtyErrCode::DbUnavailableErr,
+                _ => HtyErrCode::DbErr,
+            },
+            _ => HtyErrCode::DbErr,
+        };
+
+        HtyErr {
+            code,
+            reason: Some(err.to_string()),
+        }
+    }
+}
+
+impl From<diesel::r2d2::PoolError> for HtyErr {
+    fn from(err: diesel::r2d2::PoolError) -> Self {
+        // r2d2 only fails `get()` once `connection_timeout` has elapsed
+        HtyErr {
+            code: HtyErrCode::DbUnavailableErr,
+            reason: Some(err.to_string()),
+        }
+    }
+}
+
 #[derive(
 // AsExpression,
 Debug,
@@ -492,10 +924,17 @@ Deserialize)]
 struct ReqTypedUser<T: Debug + DeserializeOwned + Serialize + Clone> {
     id: Option<String>,
     username: Option<String>,
+    // RFC 3339, ignored in requests
     created_at: Option<String>,
+    updated_at: Option<String>,
     meta: Option<TypedMeta<T>>,
 }
 
+// the format chrono's serde uses for `TypedUser`'s timestamps, e.g. `2022-05-05T11:24:44.123456Z`
+fn rfc3339(t: &DateTime<Utc>) -> String {
+    t.to_rfc3339_opts(SecondsFormat::AutoSi, true)
+}
+
 
 #[derive(AsExpression, FromSqlRow, Debug, Default, Serialize, Deserialize, PartialEq, Clone)]
 #[diesel(sql_type = Jsonb)]
@@ -513,8 +952,6 @@ PartialEq,
 Serialize,
 Deserialize,
 Queryable,
-Insertable,
-Debug,
 Clone,
 AsChangeset,
 )]
@@ -523,8 +960,68 @@ AsChangeset,
 pub struct TypedUser<T: Debug + DeserializeOwned + Serialize + Clone> {
     id: String,
     username: String,
-    created_at: Option<NaiveDateTime>,
+    // both timestamps are set by the database: `created_at` defaults to `now()`,
+    // `updated_at` is kept current by the `diesel_manage_updated_at` trigger
+    created_at: DateTime<Utc>,
     meta: Option<TypedMeta<T>>,
+    #[serde(skip)]
+    password_hash: Option<String>,
+    // set while soft-deleted
+    #[serde(default)]
+    deleted_at: Option<DateTime<Utc>>,
+    updated_at: DateTime<Utc>,
+    // bumped by a trigger on every update, served as the `ETag`
+    version: i32,
+}
+
+// The columns a new user is inserted with, the database fills in the rest of `TypedUser`.
+#[derive(Insertable, Clone)]
+#[diesel(table_name = users)]
+pub struct NewTypedUser<T: Debug + DeserializeOwned + Serialize + Clone> {
+    id: String,
+    username: String,
+    meta: Option<TypedMeta<T>>,
+    password_hash: Option<String>,
+}
+
+impl<T: Debug + DeserializeOwned + Serialize + Clone> fmt::Debug for NewTypedUser<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NewTypedUser")
+            .field("id", &self.id)
+            .field("username", &self.username)
+            .field("meta", &self.meta)
+            .field("password_hash", &self.password_hash.as_ref().map(|_| "<redacted>"))
+            .finish()
+    }
+}
+
+
+impl<T: Debug + DeserializeOwned + Serialize + Clone> fmt::Debug for TypedUser<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TypedUser")
+            .field("id", &self.id)
+            .field("username", &self.username)
+            .field("created_at", &self.created_at)
+            .field("meta", &self.meta)
+            .field("password_hash", &self.password_hash.as_ref().map(|_| "<redacted>"))
+            .field("deleted_at", &self.deleted_at)
+            .field("updated_at", &self.updated_at)
+            .field("version", &self.version)
+            .finish()
+    }
+}
+
+// `users::all_columns` with `password_hash` read as `NULL`, only login and change-password need the hash.
+type UserColumns = (users::id, users::username, users::created_at, users::meta, SqlLiteral<Nullable<Varchar>>, users::deleted_at, users::updated_at, users::version);
+
+fn user_columns() -> UserColumns {
+    (users::id, users::username, users::created_at, users::meta, sql("NULL"), users::deleted_at, users::updated_at, users::version)
+}
+
+impl<T: Debug + DeserializeOwned + Serialize + Clone> Keyset for TypedUser<T> {
+    fn keyset(&self) -> (DateTime<Utc>, String) {
+        (self.created_at, self.id.clone())
+    }
 }
 
 
@@ -540,40 +1037,135 @@ pub struct ReqWxMessageDataValue {
 }
 
 
+// `version = ANY(expected)` for updates sent with an `If-Match`, always true without one
+fn version_matches(expected: &Option<Vec<i32>>) -> Box<dyn BoxableExpression<users::table, Pg, SqlType = Bool>> {
+    match expected {
+        Some(versions) => Box::new(users::version.eq_any(versions.clone())),
+        None => Box::new(true.into_sql::<Bool>()),
+    }
+}
+
+// An update matched no row: 412 if the user is still there, i.e. `If-Match` named an older version, 404 otherwise.
+fn missed_update(conn: &mut PgConnection, id_user: &String) -> HtyErr {
+    let current = users::table.find(id_user)
+        .filter(users::deleted_at.is_null())
+        .select(users::version)
+        .first::<i32>(conn)
+        .optional();
+
+    match current {
+        Ok(Some(version)) => HtyErr {
+            code: HtyErrCode::PreconditionFailedErr,
+            reason: Some(format!("user {} was modified, current version is {}", id_user, version)),
+        },
+        Ok(None) => diesel::result::Error::NotFound.into(),
+        Err(e) => e.into(),
+    }
+}
+
 impl<T: Debug + DeserializeOwned + Serialize + Clone + 'static> TypedUser<T> {
-    pub fn db_delete_typed_user<U: Debug + DeserializeOwned + Serialize + Clone + 'static>(conn: &mut PgConnection, id_user: &String) -> anyhow::Result<TypedUser<U>> {
-        let to_delete = TypedUser::find_typed_user_by_id(id_user, conn)?;
+    // Soft delete: a single `UPDATE ... RETURNING` checks `If-Match` and marks the row, the locked read before it
+    // only feeds the audit log. The row is purged once past the retention period (see `purge`).
+    pub fn db_delete_typed_user<U: Debug + DeserializeOwned + Serialize + Clone + 'static>(conn: &mut PgConnection, id_user: &String, expected: &Option<Vec<i32>>, actor: &Actor) -> Result<TypedUser<U>, HtyErr> {
+        conn.transaction(|conn| {
+            let before = users::table.find(id_user)
+                .filter(users::deleted_at.is_null())
+                .for_update()
+                .first::<TypedUser<U>>(conn)?;
+
+            let deleted = diesel::update(users::table.find(id_user).filter(users::deleted_at.is_null()).filter(version_matches(expected)))
+                .set(users::deleted_at.eq(now))
+                .get_result::<TypedUser<U>>(conn)
+                .optional()?
+                .ok_or_else(|| missed_update(conn, id_user))?;
+
+            // a deleted user can't log in, so they can't keep refreshing either
+            RefreshToken::db_revoke_user(conn, id_user)?;
+            audit::db_record(conn, actor, AuditAction::Delete, id_user, Some(&before), Some(&deleted))?;
+            Ok(deleted)
+        })
+    }
+
+    pub fn db_restore_typed_user(conn: &mut PgConnection, id_user: &String, actor: &Actor) -> Result<TypedUser<T>, HtyErr> {
+        conn.transaction(|conn| {
+            let before = users::table.find(id_user)
+                .filter(users::deleted_at.is_not_null())
+                .for_update()
+                .first::<TypedUser<T>>(conn)?;
+
+            let restored = diesel::update(users::table.find(id_user))
+                .set(users::deleted_at.eq(None::<DateTime<Utc>>))
+                .get_result::<TypedUser<T>>(conn)?;
 
+            audit::db_record(conn, actor, AuditAction::Restore, id_user, Some(&before), Some(&restored))?;
+            Ok(restored)
+        })
+    }
+
+    // `expected` are the versions from `If-Match`, see `version_matches`
+    pub fn db_update_typed_user(conn: &mut PgConnection, id_user: &String, in_username: &String, in_meta: &Option<TypedMeta<T>>, expected: &Option<Vec<i32>>, actor: &Actor) -> Result<TypedUser<T>, HtyErr> {
         use crate::schema::users::dsl::*;
-        match diesel::delete(users.find(id_user)).execute(conn) {
-            Ok(_) => Ok(to_delete),
-            Err(e) => Err(anyhow!(HtyErr {
-    code: HtyErrCode::DbErr,
-    reason: Some(e.to_string()),
-    })),
-        }
+        conn.transaction(|conn| {
+            let before = users.find(id_user)
+                .filter(deleted_at.is_null())
+                .for_update()
+                .first::<TypedUser<T>>(conn)?;
+
+            let updated = diesel::update(users.find(id_user).filter(version_matches(expected)))
+                .set((username.eq(in_username), meta.eq(in_meta)))
+                .get_result::<TypedUser<T>>(conn)
+                .optional()?
+                .ok_or_else(|| missed_update(conn, id_user))?;
+
+            audit::db_record(conn, actor, AuditAction::Update, id_user, Some(&before), Some(&updated))?;
+            Ok(updated)
+        })
     }
 
-    pub fn find_typed_user_by_id(id_user: &String, conn: &mut PgConnection) -> anyhow::Result<TypedUser<T>> {
+    pub fn db_patch_typed_user(conn: &mut PgConnection, id_user: &String, patch: &ReqPatchUser, expected: &Option<Vec<i32>>, actor: &Actor) -> Result<TypedUser<T>, HtyErr> {
+        conn.transaction(|conn| {
+            let current = users::table.find(id_user)
+                .filter(users::deleted_at.is_null())
+                .for_update()
+                .first::<TypedUser<T>>(conn)?;
+
+            let in_username = patch.username.clone().unwrap_or(current.username);
+            let in_meta = match &patch.meta {
+                Some(Some(meta_patch)) => {
+                    let mut merged = serde_json::to_value(&current.meta).map_err(internal_error)?;
+                    json_merge_patch(&mut merged, meta_patch);
+                    serde_json::from_value(merged).map_err(|e| HtyErr {
+                        code: HtyErrCode::CommonError,
+                        reason: Some(format!("invalid meta: {}", e)),
+                    })?
+                }
+                Some(None) => None,
+                None => current.meta,
+            };
+
+            Self::db_update_typed_user(conn, id_user, &in_username, &in_meta, expected, actor)
+        })
+    }
+
+    pub fn find_typed_user_by_id(id_user: &String, include_deleted: bool, conn: &mut PgConnection) -> Result<TypedUser<T>, HtyErr> {
         // use crate::schema::users::dsl::*;
         // use crate::schema::users::dsl::*;
-        match users::table.filter(users::id.eq(id_user))
-            .select(users::all_columns).first::<TypedUser<T>>(conn)
+        let mut query = users::table.filter(users::id.eq(id_user)).into_boxed();
+        if !include_deleted {
+            query = query.filter(users::deleted_at.is_null());
+        }
+        match query.select(user_columns()).first::<TypedUser<T>>(conn)
         {
             Ok(user) => Ok(user),
             Err(e) => Err({
                 error!("find_by_id / err -> {:?}", e);
-                anyhow!(HtyErr {
-                    code: HtyErrCode::DbErr,
-                    reason: Some(e.to_string()),
-                })
+                HtyErr::from(e)
             }),
         }
     }
 }
 
 #[derive(
-Debug,
 Serialize,
 Deserialize)]
 struct ReqUser {
@@ -581,6 +1173,61 @@ struct ReqUser {
     username: Option<String>,
     created_at: Option<String>,
     meta: Option<Meta>,
+    // write-only, never echoed back
+    #[serde(skip_serializing)]
+    password: Option<String>,
+}
+
+impl fmt::Debug for ReqUser {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReqUser")
+            .field("id", &self.id)
+            .field("username", &self.username)
+            .field("created_at", &self.created_at)
+            .field("meta", &self.meta)
+            .field("password", &self.password.as_ref().map(|_| "<redacted>"))
+            .finish()
+    }
+}
+
+// `username` and `meta` are optional, absent fields are left untouched. `"meta": null` clears meta.
+#[derive(
+Debug,
+Serialize,
+Deserialize)]
+pub struct ReqPatchUser {
+    username: Option<String>,
+    #[serde(default, deserialize_with = "double_option", skip_serializing_if = "Option::is_none")]
+    meta: Option<Option<Value>>,
+}
+
+// `Some(None)` for an explicit `null`, an absent field stays `None` through `#[serde(default)]`
+fn double_option<'de, T, D>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+    where
+        T: Deserialize<'de>,
+        D: Deserializer<'de>, {
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+// https://datatracker.ietf.org/doc/html/rfc7396
+fn json_merge_patch(target: &mut Value, patch: &Value) {
+    match patch {
+        Value::Object(patch_map) => {
+            if !target.is_object() {
+                *target = Value::Object(serde_json::Map::new());
+            }
+            if let Value::Object(target_map) = target {
+                for (k, v) in patch_map {
+                    if v.is_null() {
+                        target_map.remove(k);
+                    } else {
+                        json_merge_patch(target_map.entry(k.clone()).or_insert(Value::Null), v);
+                    }
+                }
+            }
+        }
+        _ => *target = patch.clone(),
+    }
 }
 
 #[derive(
@@ -590,7 +1237,6 @@ Serialize,
 Deserialize,
 Queryable,
 Insertable,
-Debug,
 Clone,
 AsChangeset,
 )]
@@ -598,8 +1244,32 @@ AsChangeset,
 struct User {
     id: String,
     username: String,
-    created_at: Option<NaiveDateTime>,
+    #[diesel(skip_insertion)]
+    created_at: DateTime<Utc>,
     meta: Option<Meta>,
+    #[serde(skip)]
+    password_hash: Option<String>,
+    #[serde(default)]
+    deleted_at: Option<DateTime<Utc>>,
+    #[diesel(skip_insertion)]
+    updated_at: DateTime<Utc>,
+    #[diesel(skip_insertion)]
+    version: i32,
+}
+
+impl fmt::Debug for User {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("User")
+            .field("id", &self.id)
+            .field("username", &self.username)
+            .field("created_at", &self.created_at)
+            .field("meta", &self.meta)
+            .field("password_hash", &self.password_hash.as_ref().map(|_| "<redacted>"))
+            .field("deleted_at", &self.deleted_at)
+            .field("updated_at", &self.updated_at)
+            .field("version", &self.version)
+            .finish()
+    }
 }
 
 
@@ -678,40 +1348,59 @@ macro_rules! impl_typed_jsonb_boilerplate {
 
 impl_typed_jsonb_boilerplate!(TypedMeta);
 
-fn all_users(conn: &mut PgConnection) -> Vec<User> {
+fn all_users(conn: &mut PgConnection) -> Result<Vec<User>, HtyErr> {
     use crate::schema::users::dsl::*;
-    users.load::<User>(conn).unwrap()
+    users.filter(deleted_at.is_null()).select(user_columns()).load::<User>(conn).map_err(HtyErr::from)
 }
 
-#[derive(Debug, Deserialize)]
-pub struct PageParams {
-    pub page: Option<i64>,
-    pub page_size: Option<i64>,
-    pub start_from: Option<NaiveDateTime>,
-}
-
-fn paginate_users<T: Debug + DeserializeOwned + Serialize + Clone + 'static>(params: &PageParams, conn: &mut PgConnection) -> anyhow::Result<(Vec<TypedUser<T>>, i64, i64)> {
+fn paginate_users<T: Debug + DeserializeOwned + Serialize + Clone + 'static>(params: &PageParams, filter: &UserFilter, conn: &mut PgConnection) -> Result<Page<TypedUser<T>>, HtyErr> {
     use crate::pagination::*;
-    use diesel::prelude::*;
 
-    let mut _query = users::table.into_boxed();
+    let _query = filter.sort(filter.filter(users::table.select(user_columns()).into_boxed()));
+
+    debug!("paginate_users -> params: {:?} / filter: {:?}", params, filter);
 
-    debug!("paginate_users -> params: {:?}", params);
+    let paginated = _query
+        .paginate(Some(params.page))
+        .per_page(Some(params.page_size));
 
-    let r = _query
-        .order(users::created_at.desc())
-        // .filter(users::created_at.ge(params.start_from.unwrap()))
-        // .load_with_pagination(&conn, params.page, params.page_size)?;
-        .paginate(params.page.clone())
-        .per_page(params.page_size.clone())
+    let r = paginated
+        .count_by(params.count, "users")
         .load_and_count_pages::<TypedUser<T>>(conn);
 
     debug!("paginate_users -> {:?}", r);
 
-    let (_users, _total_pages, _total) = r?;
+    let page = r?;
+
+    debug!("users: {:?} / total: {:?}", page.items, page.total);
+    Ok(page)
+}
+
+fn search_users<T: Debug + DeserializeOwned + Serialize + Clone + 'static>(params: &PageParams, search: &UserSearch, conn: &mut PgConnection) -> Result<Page<TypedUser<T>>, HtyErr> {
+    use crate::pagination::*;
+
+    debug!("search_users -> params: {:?} / search: {:?}", params, search);
+
+    let r = search.search(users::table.select(user_columns()).into_boxed())
+        .paginate(Some(params.page))
+        .per_page(Some(params.page_size))
+        .count_by(params.count, "users")
+        .load_and_count_pages::<TypedUser<T>>(conn)?;
+
+    Ok(r)
+}
+
+fn keyset_paginate_users<T: Debug + DeserializeOwned + Serialize + Clone + 'static>(some_cursor: Option<Cursor>, page_size: i64, filter: &UserFilter, conn: &mut PgConnection) -> Result<KeysetPage<TypedUser<T>>, HtyErr> {
+    use crate::pagination::*;
+
+    debug!("keyset_paginate_users -> cursor: {:?} / page_size: {:?} / filter: {:?}", some_cursor, page_size, filter);
+
+    let r = filter.filter(users::table.select(user_columns()).into_boxed())
+        .keyset_paginate(some_cursor)
+        .per_page(Some(page_size))
+        .load_page::<TypedUser<T>>(conn)?;
 
-    debug!("users: {:?} / total_pages: {:?} / total: {:?}", _users, _total_pages, _total);
-    Ok((_users, _total_pages, _total))
+    Ok(r)
 }
 
 #[derive(QueryableByName, Default, Serialize, Deserialize, Debug, Clone, PartialEq)]
@@ -724,46 +1413,54 @@ pub struct UserDTO {
     len_username: i32,
 }
 
-pub async fn find_all_sql_users(conn: DbConn) -> Json<MyResponse<Vec<UserDTO>>> {
+pub async fn find_all_sql_users(IncludeDeleted(include_deleted): IncludeDeleted, conn: DbConn) -> Result<Json<MyResponse<Vec<UserDTO>>>, HtyErr> {
     debug!("find_all_sql_users -> START");
 
-    let sql_users = raw_find_all_sql_users(&mut extract_conn(conn)).unwrap();
+    let sql_users = conn.interact(move |conn| raw_find_all_sql_users(conn, include_deleted)).await?;
     let resp = MyResponse {
         r: true,
         d: Some(sql_users),
         e: None,
     };
-    Json(resp)
+    Ok(Json(resp))
 }
 
-fn raw_find_all_sql_users(conn: &mut PgConnection) -> anyhow::Result<Vec<UserDTO>> {
-    let q = format!("SELECT UPPER(username) as upper_username, meta, LENGTH(username) as len_username FROM users");
+fn raw_find_all_sql_users(conn: &mut PgConnection, include_deleted: bool) -> Result<Vec<UserDTO>, HtyErr> {
+    let mut q = "SELECT UPPER(username) as upper_username, meta, LENGTH(username) as len_username FROM users".to_string();
+    if !include_deleted {
+        q.push_str(" WHERE deleted_at IS NULL");
+    }
     debug!("raw_find_all_sql_users -> q: {:?}", q);
 
     let res = sql_query(q.clone()).load(conn).optional()?;
     debug!("raw_find_all_sql_users -> res: {:?}", res);
 
-    if res.is_some() {
-        Ok(res.unwrap())
-    } else {
-        Ok(vec![])
-    }
+    Ok(res.unwrap_or_default())
 }
 
 #[tokio::main]
 async fn main() {
     dotenv().ok();
 
-    let db_state = DbState {
-        pool: pool(&env::var("DATABASE_URL").unwrap()),
+    let cli = Cli::parse();
+    let config = match AppConfig::load(cli.config.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            // the logger is configured by `config`, so there is none yet
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
     };
+    let command = cli.command.unwrap_or(Command::Serve(ServeArgs::default()));
 
-    let shared_db_state = Arc::new(db_state);
-
-    let logger_level = Level::DEBUG;
+    // admin commands only log what they changed
+    let logger_level = match command {
+        Command::Serve(_) => config.log_level,
+        _ => config.log_level.min(Level::INFO),
+    };
 
     let local_time = OffsetTime::new(
-        UtcOffset::from_hms(8, 0, 0).unwrap(),
+        config.log_utc_offset,
         format_description!("[year]-[month]-[day] [hour]:[minute]:[second].[subsecond digits:3]"),
     );
 
@@ -779,16 +1476,98 @@ async fn main() {
         .with_writer(stdout)
         .init();
 
+    debug!("config -> {:?}", config);
+    let db_state = DbState::new(&config);
+
+    match command {
+        Command::Serve(args) => serve(Arc::new(db_state), Arc::new(config), args).await,
+        command => {
+            let db = Arc::new(db_state);
+            // already logged
+            if startup::wait_for_db(&config).await.is_err() {
+                std::process::exit(1);
+            }
+            if let Err(e) = cli::run(command, db).await {
+                error!("{:?}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+async fn serve(db: MyDbState, config: MyConfigState, args: ServeArgs) {
+    let app_state = AppState {
+        db,
+        jwt: Arc::new(JwtConfig::new(&config.jwt)),
+        config: config.clone(),
+    };
+
+    // mutating routes require `Authorization: Bearer <jwt>`, see `auth::AuthUser`
+    let require_auth = || from_extractor_with_state::<AuthUser, _>(app_state.clone());
+    // ... and a role granting `permission`, see `rbac::Role::permissions`
+    let require = |permission: Permission| from_fn_with_state(PermissionGuard {
+        permission,
+        jwt: app_state.jwt.clone(),
+        db: app_state.db.clone(),
+    }, rbac::authorize);
+
+    match startup::wait_for_db(&config).await {
+        Ok(()) => {
+            // a schema ahead of this binary is refused with or without `RUN_MIGRATIONS`
+            let run_migrations = config.run_migrations;
+            let r = match DbConn::acquire(app_state.db.clone()).await {
+                Ok(conn) => conn.interact(move |conn| migrate::db_prepare_schema(conn, run_migrations)).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = r {
+                error!("migrations -> {:?}, refusing to start", e);
+                std::process::exit(1);
+            }
+        }
+        Err(_) if config.startup.degraded => {
+            warn!("startup -> serving in degraded mode, routes that need the database answer 503");
+            startup::spawn_recovery(app_state.db.clone(), config.clone());
+        }
+        // already logged
+        Err(_) => std::process::exit(1),
+    }
+
+    purge::spawn(app_state.db.clone(), &config);
+
     // build our application with a route
     let app = Router::new()
         // `GET /` goes to `root`
         .route("/", get(root))
+        .route("/health/live", get(health::live))
+        .route("/health/ready", get(health::ready))
         .route("/req_async", get(req_async))
+        .route("/login", post(auth::login))
+        .route("/token/refresh", post(auth::refresh))
+        .route("/logout", post(auth::logout))
+        .route("/register", post(register))
+        .route("/users/me/password", put(auth::change_password).route_layer(require_auth()))
         // `POST /users` goes to `create_user`
-        .route("/users", post(create_user))
-        .route("/typed_users", post(create_with_typed_user))
-        .route("/find_user_by_id/{id}", get(find_user_by_id))
-        .route("/delete_user_by_id/{id}", get(delete_user_by_id))
+        .route("/users", post(create_user).route_layer(require(Permission::UsersWrite)))
+        .route("/typed_users", post(create_with_typed_user).route_layer(require(Permission::UsersWrite)))
+        .route("/users/bulk", post(bulk::create_users)
+            .patch(bulk::patch_users)
+            .route_layer(require(Permission::UsersWrite))
+            .merge(delete(bulk::delete_users).route_layer(require(Permission::UsersDelete))))
+        .route("/users/{id}", get(find_user_by_id)
+            .merge(put(replace_user)
+                .patch(patch_user)
+                .route_layer(require(Permission::UsersWrite)))
+            .merge(delete(delete_user_by_id).route_layer(require(Permission::UsersDelete))))
+        .route("/users/{id}/restore", post(restore_user).route_layer(require(Permission::UsersDelete)))
+        .route("/users/{id}/history", get(audit::find_user_history).route_layer(require(Permission::UsersRead)))
+        .route("/users/{id}/roles", get(rbac::find_user_roles)
+            .route_layer(require(Permission::UsersRead))
+            .merge(put(rbac::set_user_roles).route_layer(require(Permission::RolesManage))))
+        // deprecated aliases of `/users/{id}`
+        .route("/find_user_by_id/{id}", get(find_user_by_id).layer(map_response(deprecated_alias)))
+        .route("/delete_user_by_id/{id}", get(delete_user_by_id)
+            .route_layer(require(Permission::UsersDelete))
+            .layer(map_response(deprecated_alias)))
         .route("/get_host", get(get_host))
         .route("/my_resp", get(my_resp))
         .route("/path/{id}", get(path))
@@ -797,20 +1576,30 @@ async fn main() {
         .route("/raw_string_post", post(raw_string_post))
         .route("/mix/{id}", post(mix))
         .route("/users", get(get_users_by_page))
+        .route("/users/search", get(search_users_by_name))
         .route("/query", get(query))
         .route("/nested_async", get(nested_async))
         .route("/play_with_raw_query", get(play_with_raw_query))
         .route("/current_time", get(get_current_time))
         .route("/req_conn", get(req_conn))
-        .route("/find_all_sql_users", get(find_all_sql_users))
-        .with_state(shared_db_state)
+        .route("/find_all_sql_users", get(find_all_sql_users).route_layer(require(Permission::UsersRead)))
+        .with_state(app_state)
         .without_v07_checks();
 
     // run our app with hyper
     // `axum::Server` is a re-export of `hyper::Server`
-    let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
-    let listener = TcpListener::bind(&addr).await.unwrap();
+    let addr = args.addr.unwrap_or(config.bind_addr);
+    let listener = match TcpListener::bind(&addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("listen -> can't bind {}: {}", addr, e);
+            std::process::exit(1);
+        }
+    };
     debug!("listening on {}", addr);
 
-    let _ = axum::serve(listener, app.into_make_service()).await.unwrap();
+    if let Err(e) = axum::serve(listener, app.into_make_service()).await {
+        error!("serve -> {}", e);
+        std::process::exit(1);
+    }
 }
\ No newline at end of file
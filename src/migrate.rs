@@ -0,0 +1,149 @@
+use std::collections::HashSet;
+use diesel::migration::{Migration, MigrationSource};
+use diesel::pg::Pg;
+use diesel::sql_types::{BigInt, Text};
+use diesel::{sql_query, PgConnection, RunQueryDsl};
+use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+use tracing::{error, info, warn};
+use crate::{HtyErr, HtyErrCode};
+
+// `migrations/`, compiled into the binary
+pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
+
+// Key of the advisory lock held while migrating, so replicas starting together apply each migration once.
+const MIGRATION_LOCK_ID: i64 = 0x6d69_6772_6174_6531;
+
+fn migration_err<E: ToString>(err: E) -> HtyErr {
+    HtyErr {
+        code: HtyErrCode::DbErr,
+        reason: Some(err.to_string()),
+    }
+}
+
+pub struct MigrationStatus {
+    // every embedded migration, oldest first, and whether it has been applied
+    pub known: Vec<(String, bool)>,
+    // applied migrations this binary doesn't know, i.e. the database was migrated by a newer release
+    pub unknown: Vec<String>,
+}
+
+impl MigrationStatus {
+    pub fn pending(&self) -> usize {
+        self.known.iter().filter(|(_, applied)| !applied).count()
+    }
+}
+
+pub fn db_migration_status(conn: &mut PgConnection) -> Result<MigrationStatus, HtyErr> {
+    let applied: HashSet<String> = conn.applied_migrations()
+        .map_err(migration_err)?
+        .iter()
+        .map(|v| v.to_string())
+        .collect();
+
+    let migrations = MigrationSource::<Pg>::migrations(&MIGRATIONS).map_err(migration_err)?;
+    let known: Vec<(String, bool)> = migrations.iter()
+        .map(|m| (m.name().to_string(), applied.contains(&m.name().version().to_string())))
+        .collect();
+
+    let versions: HashSet<String> = migrations.iter().map(|m| m.name().version().to_string()).collect();
+    let mut unknown: Vec<String> = applied.into_iter().filter(|v| !versions.contains(v)).collect();
+    unknown.sort();
+
+    Ok(MigrationStatus { known, unknown })
+}
+
+#[derive(QueryableByName)]
+struct Setting {
+    #[diesel(sql_type = Text)]
+    value: String,
+}
+
+fn ensure_not_ahead(status: &MigrationStatus) -> Result<(), HtyErr> {
+    if status.unknown.is_empty() {
+        return Ok(());
+    }
+    Err(HtyErr {
+        code: HtyErrCode::DbErr,
+        reason: Some(format!("database schema is ahead of this binary, unknown migrations: {}", status.unknown.join(", "))),
+    })
+}
+
+// For a server that doesn't migrate itself: refuses a schema that is ahead of this binary, pending migrations are
+// only reported since they may be applied by another instance or `migrate up`.
+pub fn db_check_schema(conn: &mut PgConnection) -> Result<(), HtyErr> {
+    let status = db_migration_status(conn)?;
+    ensure_not_ahead(&status)?;
+    if status.pending() > 0 {
+        warn!("migrations -> {} pending, see `migrate status`", status.pending());
+    }
+    Ok(())
+}
+
+// Runs the cleanup after `r` was computed, an error of `r` is returned ahead of a cleanup error, which is only logged.
+fn and_cleanup<R>(r: Result<R, HtyErr>, cleanup: Result<usize, diesel::result::Error>, what: &str) -> Result<R, HtyErr> {
+    match (r, cleanup) {
+        (r, Ok(_)) => r,
+        (Ok(_), Err(e)) => Err(HtyErr::from(e)),
+        (Err(e), Err(cleanup_err)) => {
+            error!("migrations -> {} failed as well: {}", what, cleanup_err);
+            Err(e)
+        }
+    }
+}
+
+// Runs `f` holding `MIGRATION_LOCK_ID`, refusing to touch a schema that is ahead of this binary.
+fn with_migration_lock<F, R>(conn: &mut PgConnection, f: F) -> Result<R, HtyErr>
+    where
+        F: FnOnce(&mut PgConnection) -> Result<R, HtyErr>,
+{
+    // waiting for the lock and migrating may take longer than `SESSION_STATEMENT_TIMEOUT_MS`
+    let statement_timeout = sql_query("select current_setting('statement_timeout') as value").get_result::<Setting>(conn)?;
+    sql_query("set statement_timeout = 0").execute(conn)?;
+
+    let r = sql_query("select pg_advisory_lock($1)").bind::<BigInt, _>(MIGRATION_LOCK_ID).execute(conn)
+        .map_err(HtyErr::from)
+        .and_then(|_| {
+            let r = db_migration_status(conn).and_then(|status| ensure_not_ahead(&status)).and_then(|_| f(conn));
+            // session level lock: release it even if migrating failed, the connection goes back to the pool
+            let unlocked = sql_query("select pg_advisory_unlock($1)").bind::<BigInt, _>(MIGRATION_LOCK_ID).execute(conn);
+            and_cleanup(r, unlocked, "releasing the migration lock")
+        });
+
+    let restored = sql_query("select set_config('statement_timeout', $1, false)").bind::<Text, _>(statement_timeout.value).execute(conn);
+    and_cleanup(r, restored, "restoring statement_timeout")
+}
+
+// Applies the pending migrations and returns their versions.
+pub fn db_run_pending_migrations(conn: &mut PgConnection) -> Result<Vec<String>, HtyErr> {
+    let applied = with_migration_lock(conn, |conn| {
+        let applied = conn.run_pending_migrations(MIGRATIONS).map_err(migration_err)?;
+        Ok(applied.iter().map(|v| v.to_string()).collect::<Vec<String>>())
+    })?;
+
+    if applied.is_empty() {
+        info!("migrations -> schema is up to date");
+    }
+    for version in &applied {
+        info!("migrations -> applied {}", version);
+    }
+    Ok(applied)
+}
+
+// What a starting server does with the schema: migrate it with `RUN_MIGRATIONS`, otherwise only check it.
+pub fn db_prepare_schema(conn: &mut PgConnection, run_migrations: bool) -> Result<(), HtyErr> {
+    if run_migrations {
+        db_run_pending_migrations(conn).map(|_| ())
+    } else {
+        db_check_schema(conn)
+    }
+}
+
+// Reverts the most recently applied migration and returns its version.
+pub fn db_revert_last_migration(conn: &mut PgConnection) -> Result<String, HtyErr> {
+    let reverted = with_migration_lock(conn, |conn| {
+        conn.revert_last_migration(MIGRATIONS).map(|v| v.to_string()).map_err(migration_err)
+    })?;
+
+    info!("migrations -> reverted {}", reverted);
+    Ok(reverted)
+}
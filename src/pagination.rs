@@ -1,28 +1,131 @@
+use axum::extract::FromRequestParts;
+use axum::http::request::Parts;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
 use diesel::pg::Pg;
 use diesel::prelude::*;
 use diesel::query_builder::*;
 use diesel::query_dsl::methods::LoadQuery;
-use diesel::sql_types::BigInt;
+use diesel::sql_types::{BigInt, Text, Timestamptz, Varchar};
+use serde::{Deserialize, Serialize};
 use std::fmt::Debug;
+use crate::{query_params, HtyErr, HtyErrCode};
 
 pub trait Paginate: Sized {
     fn paginate(self, page: Option<i64>) -> Paginated<Self>;
+    fn keyset_paginate(self, cursor: Option<Cursor>) -> KeysetPaginated<Self>;
 }
 
 impl<T> Paginate for T {
     fn paginate(self, page: Option<i64>) -> Paginated<Self> {
-        let offset = if page.is_some() { (page.clone().unwrap() - 1) * DEFAULT_PER_PAGE } else { -1 };
+        let offset = page.map(|p| (p - 1).saturating_mul(DEFAULT_PER_PAGE)).unwrap_or(-1);
         Paginated {
             query: self,
             some_per_page: Some(DEFAULT_PER_PAGE),
             per_page: DEFAULT_PER_PAGE,
             page,
             offset,
+            count: CountMode::Exact,
+        }
+    }
+
+    fn keyset_paginate(self, cursor: Option<Cursor>) -> KeysetPaginated<Self> {
+        KeysetPaginated {
+            query: self,
+            cursor,
+            per_page: DEFAULT_PER_PAGE,
         }
     }
 }
 
 const DEFAULT_PER_PAGE: i64 = 10;
+const MAX_PER_PAGE: i64 = 100;
+
+// Query string of paginated listings, validated when extracted.
+#[derive(Debug, Deserialize)]
+pub struct PageParams {
+    #[serde(default = "first_page")]
+    pub page: i64,
+    #[serde(default = "default_per_page")]
+    pub page_size: i64,
+    pub count: Option<PageCountParam>,
+    // `?cursor=` (empty) switches to keyset pagination, `page` is ignored then
+    pub cursor: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PageCountParam {
+    Exact,
+    Estimated,
+    None,
+}
+
+fn first_page() -> i64 {
+    1
+}
+
+fn default_per_page() -> i64 {
+    DEFAULT_PER_PAGE
+}
+
+impl PageParams {
+    pub fn validate(self) -> Result<Self, HtyErr> {
+        if self.page < 1 {
+            return Err(HtyErr {
+                code: HtyErrCode::CommonError,
+                reason: Some(format!("invalid `page`: {}, expected at least 1", self.page)),
+            });
+        }
+        if !(1..=MAX_PER_PAGE).contains(&self.page_size) {
+            return Err(HtyErr {
+                code: HtyErrCode::CommonError,
+                reason: Some(format!("invalid `page_size`: {}, expected 1 to {}", self.page_size, MAX_PER_PAGE)),
+            });
+        }
+        // the offset is `(page - 1) * page_size`
+        if (self.page - 1).checked_mul(self.page_size).is_none() {
+            return Err(HtyErr {
+                code: HtyErrCode::CommonError,
+                reason: Some(format!("invalid `page`: {}, too large for a `page_size` of {}", self.page, self.page_size)),
+            });
+        }
+        Ok(self)
+    }
+}
+
+impl<S> FromRequestParts<S> for PageParams
+    where
+        S: Send + Sync, {
+    type Rejection = HtyErr;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        query_params::<PageParams>(parts)?.validate()
+    }
+}
+
+// How `Paginated` computes `total`, exact counting scans every matching row.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CountMode {
+    // `COUNT(*) OVER ()`
+    Exact,
+    // `pg_class.reltuples` of the given table as of the last VACUUM / ANALYZE, ignoring any filter
+    Estimated(&'static str),
+    // no total at all, only `has_next`
+    Skip,
+}
+
+// One page of an offset paginated listing, `total` and `total_pages` are null when not counted.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Page<U> {
+    pub items: Vec<U>,
+    pub page: i64,
+    pub page_size: i64,
+    pub total: Option<i64>,
+    pub total_pages: Option<i64>,
+    pub has_next: bool,
+}
 
 #[derive(Debug, Clone, Copy, QueryId)]
 pub struct Paginated<T> {
@@ -31,12 +134,16 @@ pub struct Paginated<T> {
     some_per_page: Option<i64>,
     per_page: i64,
     offset: i64,
+    count: CountMode,
 }
 
 impl<T> Paginated<T> {
     pub fn per_page(self, some_per_page: Option<i64>) -> Self {
-        let per_page = if some_per_page.is_some() { some_per_page.clone().unwrap() } else { -1 };
-        let offset = if some_per_page.is_some() && self.page.is_some() { (self.page.clone().unwrap() - 1) * some_per_page.clone().unwrap() } else { -1 };
+        let per_page = some_per_page.unwrap_or(-1);
+        let offset = match (self.page, some_per_page) {
+            (Some(page), Some(per_page)) => (page - 1).saturating_mul(per_page),
+            _ => -1,
+        };
 
         Paginated {
             some_per_page,
@@ -46,30 +153,63 @@ impl<T> Paginated<T> {
         }
     }
 
+    pub fn without_count(self) -> Self {
+        Paginated {
+            count: CountMode::Skip,
+            ..self
+        }
+    }
+
+    pub fn estimated_count(self, table: &'static str) -> Self {
+        Paginated {
+            count: CountMode::Estimated(table),
+            ..self
+        }
+    }
+
+    // applies `?count=`, estimates are read from `table`
+    pub fn count_by(self, count: Option<PageCountParam>, table: &'static str) -> Self {
+        match count {
+            Some(PageCountParam::Estimated) => self.estimated_count(table),
+            Some(PageCountParam::None) => self.without_count(),
+            Some(PageCountParam::Exact) | None => self,
+        }
+    }
+
     pub fn load_and_count_pages<'a, U: Debug>(
         self,
         conn: &mut PgConnection,
-    ) -> QueryResult<(Vec<U>, i64, i64)>
+    ) -> QueryResult<Page<U>>
         where
             Self: LoadQuery<'a, PgConnection, (U, i64)>,
     {
-        let some_page = self.page.clone();
-        let some_per_page = self.some_per_page.clone();
+        let some_page = self.page;
+        let some_per_page = self.some_per_page;
+        let count = self.count;
+        let offset = self.offset;
 
         let results = self.load::<(U, i64)>(conn);
 
-        let unwrapped_results = results?;
+        let mut unwrapped_results = results?;
 
-        if some_page.is_some() && some_per_page.is_some() {
-            let per_page = some_per_page.unwrap();
-            let total = unwrapped_results.get(0).map(|x| x.1).unwrap_or(0);
-            let records = unwrapped_results.into_iter().map(|x| x.0).collect();
-            let total_pages = (total as f64 / per_page as f64).ceil() as i64;
-            Ok((records, total_pages, total))
+        // `walk_ast` emits -1 when skipping the count, estimates are -1 for never analyzed tables. Without rows the
+        // count is only known on the first page: nothing matched. Past the last row it isn't, so it stays null.
+        let total = unwrapped_results.first()
+            .map(|x| x.1)
+            .filter(|total| *total >= 0)
+            .or(if count == CountMode::Exact && offset <= 0 { Some(0) } else { None });
+
+        if let (Some(page), Some(per_page)) = (some_page, some_per_page) {
+            // one extra row is fetched to tell whether there is a next page without counting
+            let has_next = unwrapped_results.len() as i64 > per_page;
+            unwrapped_results.truncate(per_page.max(0) as usize);
+            let items = unwrapped_results.into_iter().map(|x| x.0).collect();
+            let total_pages = total.map(|total| (total as f64 / per_page as f64).ceil() as i64);
+            Ok(Page { items, page, page_size: per_page, total, total_pages, has_next })
         } else {
-            let total = unwrapped_results.get(0).map(|x| x.1).unwrap_or(0);
-            let records = unwrapped_results.into_iter().map(|x| x.0).collect();
-            Ok((records, 1, total))
+            let items: Vec<U> = unwrapped_results.into_iter().map(|x| x.0).collect();
+            let page_size = items.len() as i64;
+            Ok(Page { items, page: 1, page_size, total, total_pages: Some(1), has_next: false })
         }
     }
 }
@@ -87,18 +227,154 @@ impl<T> QueryFragment<Pg> for Paginated<T>
     fn walk_ast<'b>(&'b self, mut out: AstPass<'_, 'b, Pg>) -> QueryResult<()> {
         // https://github.com/alchemy-studio/axum-playground/commit/f33db2654b178b7602e2c6abb4d4021ada29832c
         out.unsafe_to_cache_prepared();
+        match &self.count {
+            CountMode::Exact => out.push_sql("SELECT *, COUNT(*) OVER () FROM ("),
+            CountMode::Estimated(table) => {
+                out.push_sql("SELECT *, COALESCE((SELECT reltuples::bigint FROM pg_class WHERE oid = to_regclass(");
+                out.push_bind_param::<Text, _>(*table)?;
+                out.push_sql(")), -1) FROM (");
+            }
+            CountMode::Skip => out.push_sql("SELECT *, CAST(-1 AS BIGINT) FROM ("),
+        }
+        self.query.walk_ast(out.reborrow())?;
+        out.push_sql(") t");
         if self.page.is_some() && self.some_per_page.is_some() {
-            out.push_sql("SELECT *, COUNT(*) OVER () FROM (");
-            self.query.walk_ast(out.reborrow())?;
-            out.push_sql(") t LIMIT ");
+            out.push_sql(" LIMIT ");
             out.push_bind_param::<BigInt, _>(&self.per_page)?;
-            out.push_sql(" OFFSET ");
+            out.push_sql(" + 1 OFFSET ");
             out.push_bind_param::<BigInt, _>(&self.offset)?;
+        }
+        Ok(())
+    }
+}
+
+// ------------------------------------------------------------------------------------------------
+// Keyset (seek) pagination over `(created_at, id)`, newest first.
+// https://use-the-index-luke.com/no-offset
+
+// Rows that can be keyset paginated expose their `(created_at, id)` key.
+pub trait Keyset {
+    fn keyset(&self) -> (DateTime<Utc>, String);
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Cursor {
+    pub created_at: DateTime<Utc>,
+    pub id: String,
+    // `true` to walk back to the rows listed before this key
+    #[serde(default)]
+    pub before: bool,
+}
+
+impl Cursor {
+    fn from_keyset<U: Keyset>(row: &U, before: bool) -> Self {
+        let (created_at, id) = row.keyset();
+        Cursor {
+            created_at,
+            id,
+            before,
+        }
+    }
+
+    // opaque to clients: url-safe base64 of the json encoded key
+    pub fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).unwrap_or_default())
+    }
+
+    pub fn decode(encoded: &str) -> Option<Self> {
+        let bytes = URL_SAFE_NO_PAD.decode(encoded).ok()?;
+        serde_json::from_slice(&bytes).ok()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct KeysetPage<U> {
+    pub items: Vec<U>,
+    pub next_cursor: Option<String>,
+    pub prev_cursor: Option<String>,
+}
+
+#[derive(Debug, Clone, QueryId)]
+pub struct KeysetPaginated<T> {
+    query: T,
+    cursor: Option<Cursor>,
+    per_page: i64,
+}
+
+impl<T> KeysetPaginated<T> {
+    pub fn per_page(self, some_per_page: Option<i64>) -> Self {
+        KeysetPaginated {
+            per_page: some_per_page.unwrap_or(DEFAULT_PER_PAGE),
+            ..self
+        }
+    }
+
+    pub fn load_page<'a, U: Keyset + Debug>(
+        self,
+        conn: &mut PgConnection,
+    ) -> QueryResult<KeysetPage<U>>
+        where
+            Self: LoadQuery<'a, PgConnection, U>,
+    {
+        let per_page = self.per_page;
+        let some_cursor = self.cursor.clone();
+        let before = some_cursor.as_ref().is_some_and(|c| c.before);
+
+        // one extra row tells whether there is anything beyond this page
+        let mut items = self.load::<U>(conn)?;
+        let has_more = items.len() as i64 > per_page;
+        items.truncate(per_page.max(0) as usize);
+
+        // walking back reads in ascending order, flip it to the listing order
+        let (has_next, has_prev) = if before {
+            items.reverse();
+            (true, has_more)
         } else {
-            out.push_sql("SELECT *, COUNT(*) OVER () FROM (");
-            self.query.walk_ast(out.reborrow())?;
-            out.push_sql(") t");
+            (has_more, some_cursor.is_some())
+        };
+
+        let next_cursor = items.last()
+            .filter(|_| has_next)
+            .map(|row| Cursor::from_keyset(row, false).encode());
+        let prev_cursor = items.first()
+            .filter(|_| has_prev)
+            .map(|row| Cursor::from_keyset(row, true).encode());
+
+        Ok(KeysetPage {
+            items,
+            next_cursor,
+            prev_cursor,
+        })
+    }
+}
+
+impl<T: Query> Query for KeysetPaginated<T> {
+    type SqlType = T::SqlType;
+}
+
+impl<T> RunQueryDsl<PgConnection> for KeysetPaginated<T> {}
+
+impl<T> QueryFragment<Pg> for KeysetPaginated<T>
+    where
+        T: QueryFragment<Pg>,
+{
+    fn walk_ast<'b>(&'b self, mut out: AstPass<'_, 'b, Pg>) -> QueryResult<()> {
+        let before = self.cursor.as_ref().is_some_and(|c| c.before);
+
+        out.push_sql("SELECT * FROM (");
+        self.query.walk_ast(out.reborrow())?;
+        out.push_sql(") t");
+        if let Some(cursor) = &self.cursor {
+            out.push_sql(if before { " WHERE (t.created_at, t.id) > (" } else { " WHERE (t.created_at, t.id) < (" });
+            out.push_bind_param::<Timestamptz, _>(&cursor.created_at)?;
+            out.push_sql(", ");
+            out.push_bind_param::<Varchar, _>(&cursor.id)?;
+            out.push_sql(")");
         }
+        let direction = if before { "ASC" } else { "DESC" };
+        out.push_sql(&format!(" ORDER BY t.created_at {}, t.id {} LIMIT ", direction, direction));
+        out.push_bind_param::<BigInt, _>(&self.per_page)?;
+        out.push_sql(" + 1");
         Ok(())
     }
 }
@@ -0,0 +1,108 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use diesel::r2d2::{CustomizeConnection, Error, HandleError, State};
+use diesel::sql_types::Text;
+use diesel::{sql_query, PgConnection, RunQueryDsl};
+use diesel::r2d2::event::{AcquireEvent, CheckinEvent, CheckoutEvent, HandleEvent, ReleaseEvent, TimeoutEvent};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, trace, warn};
+use crate::config::SessionConfig;
+
+// Applies `SessionConfig` to every connection the pool opens, a connection that can't be set up is discarded.
+#[derive(Debug)]
+pub struct ConnectionSetup(pub SessionConfig);
+
+impl CustomizeConnection<PgConnection, Error> for ConnectionSetup {
+    fn on_acquire(&self, conn: &mut PgConnection) -> Result<(), Error> {
+        // `SET` doesn't take bind parameters, `set_config` does
+        let settings = [
+            ("statement_timeout", Some(self.0.statement_timeout_ms.to_string())),
+            ("application_name", Some(self.0.application_name.clone())),
+            ("search_path", self.0.search_path.clone()),
+        ];
+        for (name, value) in settings {
+            if let Some(value) = value {
+                sql_query("select set_config($1, $2, false)")
+                    .bind::<Text, _>(name)
+                    .bind::<Text, _>(value)
+                    .execute(conn)
+                    .map_err(Error::QueryError)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    opened: AtomicU64,
+    closed: AtomicU64,
+    checkouts: AtomicU64,
+    timeouts: AtomicU64,
+}
+
+// Logs the pool's lifecycle and keeps running totals, which every event reports along with its own fields. Clones
+// share the totals, `DbState` keeps one to report them on the health endpoints.
+#[derive(Debug, Default, Clone)]
+pub struct PoolEvents(Arc<Counters>);
+
+// The pool's current size and the totals since startup.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PoolStats {
+    pub connections: u32,
+    pub idle: u32,
+    pub opened: u64,
+    pub closed: u64,
+    pub checkouts: u64,
+    pub timeouts: u64,
+}
+
+impl PoolEvents {
+    pub fn stats(&self, state: State) -> PoolStats {
+        PoolStats {
+            connections: state.connections,
+            idle: state.idle_connections,
+            opened: self.0.opened.load(Ordering::Relaxed),
+            closed: self.0.closed.load(Ordering::Relaxed),
+            checkouts: self.0.checkouts.load(Ordering::Relaxed),
+            timeouts: self.0.timeouts.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl HandleEvent for PoolEvents {
+    fn handle_acquire(&self, event: AcquireEvent) {
+        let opened = self.0.opened.fetch_add(1, Ordering::Relaxed) + 1;
+        debug!(connection_id = event.connection_id(), opened, "pool -> connection opened");
+    }
+
+    fn handle_release(&self, event: ReleaseEvent) {
+        let closed = self.0.closed.fetch_add(1, Ordering::Relaxed) + 1;
+        debug!(connection_id = event.connection_id(), age_ms = event.age().as_millis() as u64, closed, "pool -> connection closed");
+    }
+
+    fn handle_checkout(&self, event: CheckoutEvent) {
+        let checkouts = self.0.checkouts.fetch_add(1, Ordering::Relaxed) + 1;
+        trace!(connection_id = event.connection_id(), wait_ms = event.duration().as_millis() as u64, checkouts, "pool -> checked out");
+    }
+
+    fn handle_timeout(&self, event: TimeoutEvent) {
+        let timeouts = self.0.timeouts.fetch_add(1, Ordering::Relaxed) + 1;
+        warn!(timeout_ms = event.timeout().as_millis() as u64, timeouts, "pool -> no connection available");
+    }
+
+    fn handle_checkin(&self, event: CheckinEvent) {
+        trace!(connection_id = event.connection_id(), used_ms = event.duration().as_millis() as u64, "pool -> checked in");
+    }
+}
+
+// r2d2 retries failed connections in the background and by default logs every failure as an error, a request that
+// runs out of time logs the last one with `handle_timeout` instead.
+#[derive(Debug)]
+pub struct ConnectionErrors;
+
+impl HandleError<Error> for ConnectionErrors {
+    fn handle_error(&self, error: Error) {
+        debug!("pool -> can't open connection: {}", error.to_string().split_whitespace().collect::<Vec<&str>>().join(" "));
+    }
+}
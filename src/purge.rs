@@ -0,0 +1,57 @@
+use diesel::dsl::{now, IntervalDsl};
+use diesel::sql_types::Timestamptz;
+use diesel::{Connection, ExpressionMethods, IntoSql, NullableExpressionMethods, PgConnection, QueryDsl, RunQueryDsl};
+use serde_json::Value;
+use tracing::{error, info};
+use crate::audit::{self, Actor, AuditAction};
+use crate::schema::users;
+use crate::config::AppConfig;
+use crate::{uuid, DbConn, HtyErr, MyDbState, TypedUser};
+
+// Each run has no token, like `cli::cli_actor`, its request id groups the users it removed in the audit log.
+fn purge_actor() -> Actor {
+    Actor {
+        user_id: None,
+        request_id: format!("purge-{}", uuid()),
+    }
+}
+
+pub fn db_purge_deleted_users(conn: &mut PgConnection, retention_days: i32, actor: &Actor) -> Result<usize, HtyErr> {
+    let cutoff = now.into_sql::<Timestamptz>() - retention_days.days();
+    conn.transaction(|conn| {
+        // `Value` reads any `meta`, a row the handlers can't read must not stall the purge
+        let purged = diesel::delete(users::table.filter(users::deleted_at.lt(cutoff.nullable())))
+            .get_results::<TypedUser<Value>>(conn)?;
+
+        for user in &purged {
+            audit::db_record(conn, actor, AuditAction::Purge, &user.id, Some(user), None)?;
+        }
+        Ok(purged.len())
+    })
+}
+
+// Runs in the background for the lifetime of the server, a failed run is logged and retried next interval.
+pub fn spawn(db: MyDbState, config: &AppConfig) {
+    let retention_days = config.soft_delete_retention_days;
+    let mut interval = tokio::time::interval(config.purge_interval);
+
+    tokio::spawn(async move {
+        loop {
+            interval.tick().await;
+            // nothing to do until `startup::spawn_recovery` reaches the database
+            if !db.is_available() {
+                continue;
+            }
+
+            let r = match DbConn::acquire(db.clone()).await {
+                Ok(conn) => conn.interact(move |conn| db_purge_deleted_users(conn, retention_days, &purge_actor())).await,
+                Err(e) => Err(e),
+            };
+            match r {
+                Ok(0) => {}
+                Ok(purged) => info!("purge -> removed {} users deleted more than {} days ago", purged, retention_days),
+                Err(e) => error!("purge -> {:?}", e),
+            }
+        }
+    });
+}
@@ -0,0 +1,223 @@
+use axum::extract::{FromRef, FromRequestParts, Request, State};
+use axum::http::request::Parts;
+use axum::middleware::Next;
+use axum::response::Response;
+use axum::Json;
+use diesel::{insert_into, Connection, ExpressionMethods, OptionalExtension, PgConnection, QueryDsl, RunQueryDsl};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+use crate::auth::{AuthUser, MyJwtState};
+use crate::schema::{user_roles, users};
+use crate::{query_params, DbConn, HtyErr, HtyErrCode, JsonBody, MyDbState, MyResponse, PathParam};
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Admin,
+    Operator,
+    Viewer,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    UsersRead,
+    UsersWrite,
+    UsersDelete,
+    RolesManage,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Admin => "admin",
+            Role::Operator => "operator",
+            Role::Viewer => "viewer",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Role> {
+        match name {
+            "admin" => Some(Role::Admin),
+            "operator" => Some(Role::Operator),
+            "viewer" => Some(Role::Viewer),
+            _ => None,
+        }
+    }
+
+    pub fn permissions(&self) -> &'static [Permission] {
+        match self {
+            Role::Admin => &[Permission::UsersRead, Permission::UsersWrite, Permission::UsersDelete, Permission::RolesManage],
+            Role::Operator => &[Permission::UsersRead, Permission::UsersWrite],
+            Role::Viewer => &[Permission::UsersRead],
+        }
+    }
+}
+
+pub fn has_permission(roles: &[Role], permission: Permission) -> bool {
+    roles.iter().any(|role| role.permissions().contains(&permission))
+}
+
+pub fn db_find_roles(conn: &mut PgConnection, id_user: &str) -> Result<Vec<Role>, HtyErr> {
+    let names = user_roles::table
+        .filter(user_roles::user_id.eq(id_user))
+        .select(user_roles::role)
+        .order(user_roles::role)
+        .load::<String>(conn)?;
+
+    // `user_roles.role` references `roles`, so unknown names only appear if the two drift apart
+    Ok(names.iter().filter_map(|name| Role::from_name(name)).collect())
+}
+
+// Roles of a caller who still exists. A token outlives role changes and soft deletion, so the guards ask the
+// database instead of trusting the roles in it.
+pub fn db_find_caller_roles(conn: &mut PgConnection, id_user: &str) -> Result<Vec<Role>, HtyErr> {
+    let active = users::table.find(id_user)
+        .filter(users::deleted_at.is_null())
+        .select(users::id)
+        .first::<String>(conn)
+        .optional()?;
+    if active.is_none() {
+        return Err(HtyErr {
+            code: HtyErrCode::AuthenticationFailed,
+            reason: Some("user no longer exists".to_string()),
+        });
+    }
+
+    db_find_roles(conn, id_user)
+}
+
+async fn caller_roles(db: MyDbState, auth_user: &AuthUser) -> Result<Vec<Role>, HtyErr> {
+    let id_user = auth_user.sub.clone();
+    DbConn::acquire(db).await?.interact(move |conn| db_find_caller_roles(conn, &id_user)).await
+}
+
+pub fn db_set_roles(conn: &mut PgConnection, id_user: &str, in_roles: &[Role]) -> Result<Vec<Role>, HtyErr> {
+    conn.transaction(|conn| {
+        // 404 for unknown users instead of a foreign key violation
+        users::table.find(id_user).filter(users::deleted_at.is_null()).select(users::id).first::<String>(conn)?;
+
+        diesel::delete(user_roles::table.filter(user_roles::user_id.eq(id_user))).execute(conn)?;
+
+        let rows: Vec<_> = in_roles.iter()
+            .map(|r| (user_roles::user_id.eq(id_user), user_roles::role.eq(r.as_str())))
+            .collect();
+        if !rows.is_empty() {
+            insert_into(user_roles::table)
+                .values(&rows)
+                .on_conflict_do_nothing()
+                .execute(conn)?;
+        }
+
+        db_find_roles(conn, id_user)
+    })
+}
+
+// State of the `authorize` middleware: the permission a route requires, declared in `main`.
+#[derive(Clone)]
+pub struct PermissionGuard {
+    pub permission: Permission,
+    pub jwt: MyJwtState,
+    pub db: MyDbState,
+}
+
+impl FromRef<PermissionGuard> for MyJwtState {
+    fn from_ref(guard: &PermissionGuard) -> Self {
+        guard.jwt.clone()
+    }
+}
+
+// Roles are read from `user_roles` on every request, see `db_find_caller_roles`.
+pub async fn authorize(
+    State(guard): State<PermissionGuard>,
+    auth_user: AuthUser,
+    req: Request,
+    next: Next) -> Result<Response, HtyErr> {
+    // the connection goes back to the pool before the handler asks for one
+    let roles = caller_roles(guard.db.clone(), &auth_user).await?;
+    if !has_permission(&roles, guard.permission) {
+        debug!("authorize -> {} lacks {:?}, roles: {:?}", auth_user.sub, guard.permission, roles);
+        return Err(HtyErr {
+            code: HtyErrCode::ForbiddenErr,
+            reason: Some(format!("missing permission {:?}", guard.permission)),
+        });
+    }
+
+    Ok(next.run(req).await)
+}
+
+#[derive(Deserialize)]
+struct IncludeDeletedParams {
+    #[serde(default)]
+    include_deleted: bool,
+}
+
+// `?include_deleted=true` on user lookups, only for callers who may delete (and so restore) users. Extract it before
+// `DbConn`: checking the caller's roles takes a connection of its own.
+pub struct IncludeDeleted(pub bool);
+
+impl<S> FromRequestParts<S> for IncludeDeleted
+    where
+        MyJwtState: FromRef<S>,
+        MyDbState: FromRef<S>,
+        S: Send + Sync, {
+    type Rejection = HtyErr;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let params: IncludeDeletedParams = query_params(parts)?;
+        if !params.include_deleted {
+            return Ok(IncludeDeleted(false));
+        }
+
+        let auth_user = AuthUser::from_request_parts(parts, state).await?;
+        let roles = caller_roles(MyDbState::from_ref(state), &auth_user).await?;
+        if !has_permission(&roles, Permission::UsersDelete) {
+            return Err(HtyErr {
+                code: HtyErrCode::ForbiddenErr,
+                reason: Some(format!("include_deleted requires permission {:?}", Permission::UsersDelete)),
+            });
+        }
+
+        Ok(IncludeDeleted(true))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReqRoles {
+    pub roles: Option<Vec<Role>>,
+}
+
+pub async fn find_user_roles(conn: DbConn, PathParam(id): PathParam<String>) -> Result<Json<MyResponse<Vec<Role>>>, HtyErr> {
+    let roles = conn.interact(move |conn| {
+        users::table.find(&id).filter(users::deleted_at.is_null()).select(users::id).first::<String>(conn)?;
+        db_find_roles(conn, &id)
+    }).await?;
+
+    let resp = MyResponse {
+        r: true,
+        d: Some(roles),
+        e: None,
+    };
+
+    Ok(Json(resp))
+}
+
+// `PUT /users/{id}/roles`: replaces the role set of a user.
+pub async fn set_user_roles(
+    conn: DbConn,
+    PathParam(id): PathParam<String>,
+    JsonBody(payload): JsonBody<ReqRoles>) -> Result<Json<MyResponse<Vec<Role>>>, HtyErr> {
+    let in_roles = payload.roles.ok_or(HtyErr {
+        code: HtyErrCode::NullErr,
+        reason: Some("roles is required".to_string()),
+    })?;
+
+    let roles = conn.interact(move |conn| db_set_roles(conn, &id, &in_roles)).await?;
+
+    let resp = MyResponse {
+        r: true,
+        d: Some(roles),
+        e: None,
+    };
+
+    Ok(Json(resp))
+}
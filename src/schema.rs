@@ -1,10 +1,65 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    audit_log (id) {
+        id -> Int8,
+        user_id -> Varchar,
+        action -> Varchar,
+        actor_id -> Nullable<Varchar>,
+        request_id -> Varchar,
+        before -> Nullable<Jsonb>,
+        after -> Nullable<Jsonb>,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    refresh_tokens (id) {
+        id -> Varchar,
+        token_hash -> Varchar,
+        user_id -> Varchar,
+        family_id -> Varchar,
+        expires_at -> Timestamp,
+        revoked -> Bool,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    roles (name) {
+        name -> Varchar,
+        description -> Nullable<Varchar>,
+    }
+}
+
+diesel::table! {
+    user_roles (user_id, role) {
+        user_id -> Varchar,
+        role -> Varchar,
+    }
+}
+
 diesel::table! {
     users (id) {
         id -> Varchar,
         username -> Varchar,
-        created_at -> Nullable<Timestamp>,
+        created_at -> Timestamptz,
         meta -> Nullable<Jsonb>,
+        password_hash -> Nullable<Varchar>,
+        deleted_at -> Nullable<Timestamptz>,
+        updated_at -> Timestamptz,
+        version -> Int4,
     }
 }
+
+diesel::joinable!(refresh_tokens -> users (user_id));
+diesel::joinable!(user_roles -> roles (role));
+diesel::joinable!(user_roles -> users (user_id));
+
+diesel::allow_tables_to_appear_in_same_query!(
+    audit_log,
+    refresh_tokens,
+    roles,
+    user_roles,
+    users,
+);
@@ -0,0 +1,99 @@
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use diesel::{Connection, PgConnection};
+use tokio::task;
+use tokio::time::{sleep, timeout};
+use tracing::{debug, error, info, warn};
+use crate::config::AppConfig;
+use crate::{internal_error, migrate, HtyErr, HtyErrCode, MyConfigState, MyDbState};
+
+// Delay after the first failed attempt, doubled after each further one.
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(250);
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(8);
+
+fn backoff(attempt: u32) -> Duration {
+    RETRY_BASE_DELAY.saturating_mul(2u32.saturating_pow(attempt - 1)).min(RETRY_MAX_DELAY)
+}
+
+// A single connection outside the pool, so every attempt reports its own error instead of r2d2 retrying internally.
+async fn connect(config: &AppConfig) -> Result<PgConnection, HtyErr> {
+    let url = config.database_url.clone();
+    let connecting = task::spawn_blocking(move || PgConnection::establish(&url));
+
+    match timeout(config.pool.acquire_timeout, connecting).await {
+        Ok(r) => r.map_err(internal_error)?.map_err(|e| HtyErr {
+            code: HtyErrCode::DbUnavailableErr,
+            // libpq's messages span several lines
+            reason: Some(e.to_string().split_whitespace().collect::<Vec<&str>>().join(" ")),
+        }),
+        Err(_) => Err(HtyErr {
+            code: HtyErrCode::DbUnavailableErr,
+            reason: Some(format!("no answer within {:?}", config.pool.acquire_timeout)),
+        }),
+    }
+}
+
+// Retries until the database answers or `STARTUP_DB_DEADLINE_SECS` have passed, the error is logged with the number
+// of attempts before it is returned.
+pub async fn wait_for_db(config: &AppConfig) -> Result<(), HtyErr> {
+    let deadline = config.startup.db_deadline;
+    let started = Instant::now();
+    let mut attempt = 1;
+
+    loop {
+        let err = match connect(config).await {
+            Ok(_) => {
+                if attempt > 1 {
+                    info!(attempts = attempt, elapsed_ms = started.elapsed().as_millis() as u64, "startup -> database reachable");
+                }
+                return Ok(());
+            }
+            Err(e) => e,
+        };
+
+        let elapsed = started.elapsed();
+        let error = err.reason.as_deref().unwrap_or_default();
+        if elapsed >= deadline {
+            error!(attempts = attempt, elapsed_ms = elapsed.as_millis() as u64, deadline_secs = deadline.as_secs(), error,
+                "startup -> database unreachable, giving up");
+            return Err(HtyErr {
+                code: HtyErrCode::DbUnavailableErr,
+                reason: Some(format!("database unreachable after {} attempts in {:.1}s: {}", attempt, elapsed.as_secs_f32(), err)),
+            });
+        }
+
+        // one last attempt at the deadline
+        let delay = backoff(attempt).min(deadline - elapsed);
+        warn!(attempt, retry_in_ms = delay.as_millis() as u64, error, "startup -> database unreachable, retrying");
+        sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+// Degraded mode: `DbConn::acquire` fails with 503 until the database is reachable again and its schema is ready,
+// see `migrate::db_prepare_schema`. A failed migration isn't retried, the server stays degraded until it is restarted.
+pub fn spawn_recovery(db: MyDbState, config: MyConfigState) {
+    db.available.store(false, Ordering::Relaxed);
+
+    tokio::spawn(async move {
+        let mut attempt = 1;
+        let mut conn = loop {
+            sleep(backoff(attempt)).await;
+            match connect(&config).await {
+                Ok(conn) => break conn,
+                Err(e) => debug!(attempt, error = e.reason.as_deref().unwrap_or_default(), "startup -> database still unreachable"),
+            }
+            attempt += 1;
+        };
+
+        let run_migrations = config.run_migrations;
+        let r = task::spawn_blocking(move || migrate::db_prepare_schema(&mut conn, run_migrations)).await;
+        if let Err(e) = r.map_err(internal_error).and_then(|r| r) {
+            error!("migrations -> {:?}, staying in degraded mode", e);
+            return;
+        }
+
+        db.available.store(true, Ordering::Relaxed);
+        info!(attempts = attempt, "startup -> database reachable, leaving degraded mode");
+    });
+}
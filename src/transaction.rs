@@ -0,0 +1,56 @@
+use std::thread;
+use std::time::Duration;
+use diesel::PgConnection;
+use tracing::debug;
+use crate::{DbConn, HtyErr, HtyErrCode};
+
+// Attempts of a transaction that keeps failing with a serialization failure, the last error is returned as a 409.
+const MAX_TX_ATTEMPTS: u32 = 5;
+const TX_RETRY_BASE_DELAY: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Isolation {
+    #[default]
+    ReadCommitted,
+    RepeatableRead,
+    Serializable,
+}
+
+// Runs `f` in a new transaction at `isolation`, retried while Postgres reports a serialization failure (`40001`).
+// `f` may therefore run several times and must not have side effects outside the database. `conn` must not be in a
+// transaction already, use `Connection::transaction` for savepoints.
+pub fn db_transaction<F, R>(conn: &mut PgConnection, isolation: Isolation, mut f: F) -> Result<R, HtyErr>
+    where
+        F: FnMut(&mut PgConnection) -> Result<R, HtyErr>,
+{
+    let mut attempt = 1;
+    loop {
+        let mut tx = conn.build_transaction();
+        tx = match isolation {
+            Isolation::ReadCommitted => tx.read_committed(),
+            Isolation::RepeatableRead => tx.repeatable_read(),
+            Isolation::Serializable => tx.serializable(),
+        };
+
+        match tx.run(&mut f) {
+            Err(HtyErr { code: HtyErrCode::SerializationErr, reason }) if attempt < MAX_TX_ATTEMPTS => {
+                debug!("db_transaction -> attempt {} of {} failed, retrying: {:?}", attempt, MAX_TX_ATTEMPTS, reason);
+                // we are on the blocking pool, see `DbConn::interact`
+                thread::sleep(TX_RETRY_BASE_DELAY * 2u32.pow(attempt - 1));
+                attempt += 1;
+            }
+            r => return r,
+        }
+    }
+}
+
+impl DbConn {
+    // `db_transaction` on the blocking pool, for handlers that need several statements to apply atomically.
+    pub async fn transaction<F, R>(self, isolation: Isolation, f: F) -> Result<R, HtyErr>
+        where
+            F: FnMut(&mut PgConnection) -> Result<R, HtyErr> + Send + 'static,
+            R: Send + 'static,
+    {
+        self.interact(move |conn| db_transaction(conn, isolation, f)).await
+    }
+}
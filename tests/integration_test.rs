@@ -6,6 +6,117 @@ fn generate_unique_username() -> String {
     format!("test_user_{}", Uuid::new_v4().to_string().split('-').next().unwrap())
 }
 
+// signs an admin token with the server's `JWT_SECRET`, so tests don't need to log in
+fn auth_token() -> String {
+    token_with_roles(&["admin"])
+}
+
+// the server reads the caller's roles from the database, so the token's user must exist with exactly these roles
+fn token_with_roles(roles: &[&str]) -> String {
+    dotenv::dotenv().ok();
+    let id = format!("integration-test-{}", roles.join("-"));
+    ensure_test_user(&id, roles);
+
+    let secret = std::env::var("JWT_SECRET").unwrap();
+    let now = chrono::Utc::now().timestamp();
+    let claims = json!({
+        "sub": id,
+        "username": id,
+        "roles": roles,
+        "iat": now,
+        "exp": now + 600
+    });
+
+    jsonwebtoken::encode(
+        &jsonwebtoken::Header::default(),
+        &claims,
+        &jsonwebtoken::EncodingKey::from_secret(secret.as_bytes()),
+    ).unwrap()
+}
+
+fn ensure_test_user(id: &str, roles: &[&str]) {
+    use diesel::{Connection, RunQueryDsl};
+    use diesel::sql_types::Varchar;
+
+    static READY: std::sync::Mutex<Vec<String>> = std::sync::Mutex::new(Vec::new());
+    let mut ready = READY.lock().unwrap();
+    if ready.iter().any(|r| r == id) {
+        return;
+    }
+
+    let mut conn = diesel::PgConnection::establish(&std::env::var("DATABASE_URL").unwrap()).unwrap();
+    diesel::sql_query("insert into users (id, username) values ($1, $1) on conflict (id) do update set deleted_at = null")
+        .bind::<Varchar, _>(id)
+        .execute(&mut conn)
+        .unwrap();
+    diesel::sql_query("delete from user_roles where user_id = $1")
+        .bind::<Varchar, _>(id)
+        .execute(&mut conn)
+        .unwrap();
+    for role in roles {
+        diesel::sql_query("insert into user_roles (user_id, role) values ($1, $2)")
+            .bind::<Varchar, _>(id)
+            .bind::<Varchar, _>(*role)
+            .execute(&mut conn)
+            .unwrap();
+    }
+    ready.push(id.to_string());
+}
+
+const TEST_PASSWORD: &str = "correct horse battery staple";
+
+// returns the id of the new user
+async fn register_user(client: &reqwest::Client, username: &str) -> String {
+    let response = client
+        .post("http://localhost:3000/register")
+        .json(&json!({
+            "username": username,
+            "password": TEST_PASSWORD
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 201);
+    let body: serde_json::Value = response.json().await.unwrap();
+    body["id"].as_str().unwrap().to_string()
+}
+
+async fn login(client: &reqwest::Client, username: &str, password: &str) -> reqwest::Response {
+    client
+        .post("http://localhost:3000/login")
+        .json(&json!({
+            "username": username,
+            "password": password
+        }))
+        .send()
+        .await
+        .unwrap()
+}
+
+// The binary started in a fresh directory holding `files`, with only `vars` in its environment, so the repo's `.env`
+// and `config.toml` aren't read. The directory is removed by `remove_dir_all` once the binary is done with it.
+fn binary(vars: &[(&str, &str)], files: &[(&str, &str)]) -> (std::process::Command, std::path::PathBuf) {
+    let dir = std::env::temp_dir().join(format!("axum-playground-{}", Uuid::new_v4()));
+    std::fs::create_dir(&dir).unwrap();
+    for (name, content) in files {
+        std::fs::write(dir.join(name), content).unwrap();
+    }
+
+    let mut command = std::process::Command::new(env!("CARGO_BIN_EXE_axum-playground"));
+    command.current_dir(&dir).env_clear().envs(vars.iter().copied());
+    (command, dir)
+}
+
+// Returns the exit status and everything the binary printed.
+fn run_binary(vars: &[(&str, &str)], files: &[(&str, &str)]) -> (std::process::ExitStatus, String) {
+    let (mut command, dir) = binary(vars, files);
+    let output = command.output().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let printed = format!("{}{}", String::from_utf8_lossy(&output.stdout), String::from_utf8_lossy(&output.stderr));
+    (output.status, printed)
+}
+
 #[tokio::test]
 async fn test_create_user() {
     let client = reqwest::Client::new();
@@ -13,6 +124,7 @@ async fn test_create_user() {
     
     let response = client
         .post("http://localhost:3000/users")
+        .bearer_auth(auth_token())
         .json(&json!({
             "username": username
         }))
@@ -40,10 +152,45 @@ async fn test_get_users() {
     assert_eq!(response.status().as_u16(), 200);
     let body: serde_json::Value = response.json().await.unwrap();
     assert!(body["r"].as_bool().unwrap());
-    assert!(body["d"].is_array());
+    assert!(body["d"]["items"].is_array());
+    assert_eq!(body["d"]["page"], 1);
+    assert_eq!(body["d"]["page_size"], 10);
     assert!(body["e"].is_null());
 }
 
+#[tokio::test]
+async fn test_get_users_rejects_bad_page_params() {
+    let client = reqwest::Client::new();
+
+    let id = register_user(&client, &generate_unique_username()).await;
+    let history = format!("/users/{}/history?", id);
+    let overflowing = "page=9223372036854775807";
+    for url in ["/users?page=abc", "/users?page=0", "/users?page_size=0", "/users?page_size=1000", "/users?count=bogus",
+        &format!("/users?{}", overflowing), &format!("/users/search?q=a&{}", overflowing), &format!("{}{}", history, overflowing)] {
+        let response = client
+            .get(format!("http://localhost:3000{}", url))
+            .bearer_auth(auth_token())
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status().as_u16(), 400, "{}", url);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert!(!body["r"].as_bool().unwrap());
+        assert!(body["e"].as_str().unwrap().starts_with("CommonError"));
+    }
+
+    // far beyond the last row, but the offset fits
+    let response = client
+        .get("http://localhost:3000/users?page=922337203685477581&page_size=1")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["d"]["items"].as_array().unwrap().len(), 0);
+}
+
 #[tokio::test]
 async fn test_find_all_sql_users() {
     let client = reqwest::Client::builder()
@@ -55,6 +202,7 @@ async fn test_find_all_sql_users() {
     let username = generate_unique_username();
     let _ = client
         .post("http://localhost:3000/users")
+        .bearer_auth(auth_token())
         .json(&json!({
             "username": username
         }))
@@ -67,6 +215,7 @@ async fn test_find_all_sql_users() {
     // Now try to get all SQL users
     let response = client
         .get("http://localhost:3000/find_all_sql_users")
+        .bearer_auth(auth_token())
         .send()
         .await
         .unwrap();
@@ -76,4 +225,1510 @@ async fn test_find_all_sql_users() {
     assert!(body["r"].as_bool().unwrap());
     assert!(body["d"].is_array());
     assert!(body["e"].is_null());
-} 
\ No newline at end of file
+} 
+
+#[tokio::test]
+async fn test_find_missing_user_returns_error_envelope() {
+    let client = reqwest::Client::new();
+
+    let response = client
+        .get(format!("http://localhost:3000/find_user_by_id/{}", Uuid::new_v4()))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 404);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert!(!body["r"].as_bool().unwrap());
+    assert!(body["d"].is_null());
+    assert!(body["e"].is_string());
+}
+
+#[tokio::test]
+async fn test_malformed_body_and_path_return_error_envelope() {
+    let client = reqwest::Client::new();
+    let post = |body: &'static str, content_type: &'static str| client
+        .post("http://localhost:3000/users")
+        .bearer_auth(auth_token())
+        .header("content-type", content_type)
+        .body(body)
+        .send();
+
+    let responses = vec![
+        ("malformed json", post("{\"username\": ", "application/json").await.unwrap()),
+        ("wrong field type", post("{\"username\": 42}", "application/json").await.unwrap()),
+        ("no json content type", post("{\"username\": \"x\"}", "text/plain").await.unwrap()),
+        // `%FF` isn't UTF-8
+        ("undecodable path", client.get("http://localhost:3000/users/%FF").send().await.unwrap()),
+    ];
+    for (case, response) in responses {
+        assert_eq!(response.status().as_u16(), 400, "{}", case);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert!(!body["r"].as_bool().unwrap(), "{}", case);
+        assert!(body["d"].is_null(), "{}", case);
+        assert!(body["e"].as_str().unwrap().starts_with("CommonError"), "{}: {}", case, body);
+    }
+}
+
+
+#[tokio::test]
+async fn test_create_duplicate_user_returns_conflict() {
+    let client = reqwest::Client::new();
+    let username = generate_unique_username();
+
+    let first = client
+        .post("http://localhost:3000/users")
+        .bearer_auth(auth_token())
+        .json(&json!({
+            "username": username
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(first.status().as_u16(), 201);
+
+    let second = client
+        .post("http://localhost:3000/users")
+        .bearer_auth(auth_token())
+        .json(&json!({
+            "username": username
+        }))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(second.status().as_u16(), 409);
+    let body: serde_json::Value = second.json().await.unwrap();
+    assert!(!body["r"].as_bool().unwrap());
+    assert!(body["e"].as_str().unwrap().starts_with("ConflictErr"));
+}
+
+#[tokio::test]
+async fn test_health_endpoints() {
+    let client = reqwest::Client::new();
+
+    for path in ["/health/live", "/health/ready"] {
+        let response = client
+            .get(format!("http://localhost:3000{}", path))
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status().as_u16(), 200);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert!(body["r"].as_bool().unwrap());
+        assert_eq!(body["d"]["status"], "ok");
+        for counter in ["connections", "idle", "opened", "closed", "checkouts", "timeouts"] {
+            assert!(body["d"]["pool"][counter].is_u64(), "{} missing from {}", counter, body);
+        }
+    }
+
+    // every ready check takes a connection from the pool
+    let mut checkouts = Vec::new();
+    for _ in 0..2 {
+        let body: serde_json::Value = client
+            .get("http://localhost:3000/health/ready")
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert!(body["d"]["pool"]["opened"].as_u64().unwrap() >= 1);
+        checkouts.push(body["d"]["pool"]["checkouts"].as_u64().unwrap());
+    }
+    assert!(checkouts[1] > checkouts[0]);
+}
+
+#[tokio::test]
+async fn test_concurrent_requests_share_pool() {
+    let client = reqwest::Client::new();
+
+    let requests = (0..20).map(|_| {
+        let client = client.clone();
+        tokio::spawn(async move {
+            client
+                .get("http://localhost:3000/users?page=1&page_size=10")
+                .send()
+                .await
+                .unwrap()
+                .status()
+                .as_u16()
+        })
+    });
+
+    for request in requests.collect::<Vec<_>>() {
+        assert_eq!(request.await.unwrap(), 200);
+    }
+}
+
+#[tokio::test]
+async fn test_user_resource_crud() {
+    let client = reqwest::Client::new();
+    let username = generate_unique_username();
+
+    let created: serde_json::Value = client
+        .post("http://localhost:3000/users")
+        .bearer_auth(auth_token())
+        .json(&json!({
+            "username": username
+        }))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let url = format!("http://localhost:3000/users/{}", created["id"].as_str().unwrap());
+
+    let response = client.get(&url).send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["d"]["username"], username);
+    // both timestamps are set by the database and carry a time zone
+    let timestamp = |v: &serde_json::Value| chrono::DateTime::parse_from_rfc3339(v.as_str().unwrap()).unwrap();
+    let created_at = timestamp(&body["d"]["created_at"]);
+    assert_eq!(created_at, timestamp(&body["d"]["updated_at"]));
+    assert_eq!(timestamp(&created["created_at"]), created_at);
+
+    // PATCH merges into the existing meta instead of replacing it
+    let patched_username = generate_unique_username();
+    let response = client
+        .patch(&url)
+        .bearer_auth(auth_token())
+        .json(&json!({
+            "username": patched_username,
+            "meta": { "data": { "foo": null, "baz": "2" } }
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["d"]["username"], patched_username);
+    assert_eq!(body["d"]["meta"]["data"], json!({ "bar": "1", "baz": "2" }));
+    assert_eq!(body["d"]["meta"]["meta"]["first"]["value"], "first");
+    assert_eq!(timestamp(&body["d"]["created_at"]), created_at);
+    assert!(timestamp(&body["d"]["updated_at"]) > created_at);
+
+    // an absent meta is left alone, an explicit null clears it
+    let response = client
+        .patch(&url)
+        .bearer_auth(auth_token())
+        .json(&json!({
+            "username": patched_username
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["d"]["meta"]["data"], json!({ "bar": "1", "baz": "2" }));
+
+    let response = client
+        .patch(&url)
+        .bearer_auth(auth_token())
+        .json(&json!({
+            "meta": null
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["d"]["username"], patched_username);
+    assert!(body["d"]["meta"].is_null());
+
+    // PUT replaces the row, so meta is cleared when omitted
+    let replaced_username = generate_unique_username();
+    let response = client
+        .put(&url)
+        .bearer_auth(auth_token())
+        .json(&json!({
+            "username": replaced_username
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["d"]["username"], replaced_username);
+    assert!(body["d"]["meta"].is_null());
+
+    let response = client.delete(&url).bearer_auth(auth_token()).send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+
+    let response = client.get(&url).send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 404);
+}
+
+#[tokio::test]
+async fn test_mutation_requires_token() {
+    let client = reqwest::Client::new();
+
+    let response = client
+        .post("http://localhost:3000/users")
+        .json(&json!({
+            "username": generate_unique_username()
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 401);
+
+    let response = client
+        .post("http://localhost:3000/users")
+        .bearer_auth("not-a-jwt")
+        .json(&json!({
+            "username": generate_unique_username()
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 401);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert!(body["e"].as_str().unwrap().starts_with("JwtErr"));
+}
+
+#[tokio::test]
+async fn test_login_issues_usable_token() {
+    let client = reqwest::Client::new();
+    let username = generate_unique_username();
+
+    register_user(&client, &username).await;
+
+    let response = login(&client, &username, TEST_PASSWORD).await;
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    let token = body["d"]["access_token"].as_str().unwrap().to_string();
+    assert_eq!(body["d"]["token_type"], "Bearer");
+
+    // the token is accepted, but a freshly registered user has no role yet
+    let response = client
+        .post("http://localhost:3000/users")
+        .bearer_auth(token)
+        .json(&json!({
+            "username": generate_unique_username()
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 403);
+
+    let response = login(&client, &generate_unique_username(), TEST_PASSWORD).await;
+    assert_eq!(response.status().as_u16(), 401);
+
+    let response = login(&client, &username, "wrong password").await;
+    assert_eq!(response.status().as_u16(), 401);
+
+    // a username alone is never enough
+    let response = client
+        .post("http://localhost:3000/login")
+        .json(&json!({
+            "username": username
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 400);
+
+    // neither is a user created without a password
+    let passwordless = generate_unique_username();
+    let response = client
+        .post("http://localhost:3000/users")
+        .bearer_auth(auth_token())
+        .json(&json!({
+            "username": passwordless
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 201);
+    let response = login(&client, &passwordless, "").await;
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn test_refresh_token_rotation_and_reuse() {
+    let client = reqwest::Client::new();
+    let username = generate_unique_username();
+
+    register_user(&client, &username).await;
+
+    let tokens: serde_json::Value = login(&client, &username, TEST_PASSWORD).await.json().await.unwrap();
+    let first = tokens["d"]["refresh_token"].as_str().unwrap().to_string();
+
+    let response = client
+        .post("http://localhost:3000/token/refresh")
+        .json(&json!({
+            "refresh_token": first
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    let second = body["d"]["refresh_token"].as_str().unwrap().to_string();
+    assert_ne!(first, second);
+    assert!(body["d"]["access_token"].is_string());
+
+    // replaying the rotated token revokes the whole family, including `second`
+    let response = client
+        .post("http://localhost:3000/token/refresh")
+        .json(&json!({
+            "refresh_token": first
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 401);
+
+    let response = client
+        .post("http://localhost:3000/token/refresh")
+        .json(&json!({
+            "refresh_token": second
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn test_logout_revokes_refresh_token() {
+    let client = reqwest::Client::new();
+    let username = generate_unique_username();
+
+    register_user(&client, &username).await;
+
+    let tokens: serde_json::Value = login(&client, &username, TEST_PASSWORD).await.json().await.unwrap();
+    let refresh_token = tokens["d"]["refresh_token"].as_str().unwrap().to_string();
+
+    let response = client
+        .post("http://localhost:3000/logout")
+        .json(&json!({
+            "refresh_token": refresh_token
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+
+    let response = client
+        .post("http://localhost:3000/token/refresh")
+        .json(&json!({
+            "refresh_token": refresh_token
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn test_register_never_echoes_password() {
+    let client = reqwest::Client::new();
+    let username = generate_unique_username();
+
+    let response = client
+        .post("http://localhost:3000/register")
+        .json(&json!({
+            "username": username,
+            "password": TEST_PASSWORD
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 201);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert!(body.get("password").is_none());
+    assert!(body.get("password_hash").is_none());
+
+    let response = client
+        .get(format!("http://localhost:3000/users/{}", body["id"].as_str().unwrap()))
+        .send()
+        .await
+        .unwrap();
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert!(body["d"].get("password_hash").is_none());
+
+    let response = client
+        .post("http://localhost:3000/register")
+        .json(&json!({
+            "username": generate_unique_username(),
+            "password": "short"
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 400);
+}
+
+#[tokio::test]
+async fn test_change_password() {
+    let client = reqwest::Client::new();
+    let username = generate_unique_username();
+    let id = register_user(&client, &username).await;
+
+    let body: serde_json::Value = login(&client, &username, TEST_PASSWORD).await.json().await.unwrap();
+    let access_token = body["d"]["access_token"].as_str().unwrap().to_string();
+    let refresh_token = body["d"]["refresh_token"].as_str().unwrap().to_string();
+
+    let response = client
+        .put("http://localhost:3000/users/me/password")
+        .bearer_auth(&access_token)
+        .json(&json!({
+            "old_password": "wrong password",
+            "new_password": "a brand new password"
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 401);
+
+    let response = client
+        .put("http://localhost:3000/users/me/password")
+        .bearer_auth(&access_token)
+        .json(&json!({
+            "old_password": TEST_PASSWORD,
+            "new_password": "a brand new password"
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+
+    assert_eq!(login(&client, &username, TEST_PASSWORD).await.status().as_u16(), 401);
+    assert_eq!(login(&client, &username, "a brand new password").await.status().as_u16(), 200);
+
+    // sessions opened with the old password are gone
+    let response = client
+        .post("http://localhost:3000/token/refresh")
+        .json(&json!({
+            "refresh_token": refresh_token
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 401);
+
+    // the hash never shows up in the history, only that it changed
+    let body: serde_json::Value = client
+        .get(format!("http://localhost:3000/users/{}/history", id))
+        .bearer_auth(auth_token())
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let entry = &body["d"]["items"][0];
+    assert_eq!(entry["action"], "password_change");
+    assert_eq!(entry["actor_id"], id.as_str());
+    assert!(entry["before"].get("password_hash").is_none());
+    assert!(entry["after"].get("password_hash").is_none());
+    assert_eq!(entry["after"]["version"].as_i64().unwrap(), entry["before"]["version"].as_i64().unwrap() + 1);
+}
+
+#[tokio::test]
+async fn test_role_permissions() {
+    let client = reqwest::Client::new();
+    let username = generate_unique_username();
+
+    // authenticated but without any role
+    let response = client
+        .post("http://localhost:3000/users")
+        .bearer_auth(token_with_roles(&[]))
+        .json(&json!({
+            "username": generate_unique_username()
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 403);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert!(!body["r"].as_bool().unwrap());
+    assert!(body["e"].as_str().unwrap().starts_with("ForbiddenErr"));
+
+    let response = client
+        .post("http://localhost:3000/register")
+        .json(&json!({
+            "username": username,
+            "password": TEST_PASSWORD
+        }))
+        .send()
+        .await
+        .unwrap();
+    let id = response.json::<serde_json::Value>().await.unwrap()["id"].as_str().unwrap().to_string();
+    let roles_url = format!("http://localhost:3000/users/{}/roles", id);
+
+    // only admins manage roles
+    let response = client
+        .put(&roles_url)
+        .bearer_auth(token_with_roles(&["operator"]))
+        .json(&json!({
+            "roles": ["operator"]
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 403);
+
+    let response = client
+        .put(&roles_url)
+        .bearer_auth(auth_token())
+        .json(&json!({
+            "roles": ["operator"]
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["d"], json!(["operator"]));
+
+    // operators create but cannot delete
+    let body: serde_json::Value = login(&client, &username, TEST_PASSWORD).await.json().await.unwrap();
+    let operator_token = body["d"]["access_token"].as_str().unwrap().to_string();
+
+    let response = client
+        .post("http://localhost:3000/users")
+        .bearer_auth(&operator_token)
+        .json(&json!({
+            "username": generate_unique_username()
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 201);
+    let created: serde_json::Value = response.json().await.unwrap();
+
+    let response = client
+        .delete(format!("http://localhost:3000/users/{}", created["id"].as_str().unwrap()))
+        .bearer_auth(&operator_token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 403);
+
+    // roles are checked on every request: a demotion applies to tokens already issued
+    let response = client
+        .put(&roles_url)
+        .bearer_auth(auth_token())
+        .json(&json!({
+            "roles": []
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+
+    let create = |token: String| client
+        .post("http://localhost:3000/users")
+        .bearer_auth(token)
+        .json(&json!({
+            "username": generate_unique_username()
+        }))
+        .send();
+    assert_eq!(create(operator_token.clone()).await.unwrap().status().as_u16(), 403);
+
+    // and a deleted user's tokens stop working
+    let response = client
+        .put(&roles_url)
+        .bearer_auth(auth_token())
+        .json(&json!({
+            "roles": ["operator"]
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(create(operator_token.clone()).await.unwrap().status().as_u16(), 201);
+
+    let response = client
+        .delete(format!("http://localhost:3000/users/{}", id))
+        .bearer_auth(auth_token())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(create(operator_token).await.unwrap().status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn test_get_users_with_cursor() {
+    let client = reqwest::Client::new();
+    for _ in 0..5 {
+        client
+            .post("http://localhost:3000/users")
+            .bearer_auth(auth_token())
+            .json(&json!({
+                "username": generate_unique_username()
+            }))
+            .send()
+            .await
+            .unwrap();
+    }
+
+    let get_page = |cursor: String| {
+        let client = client.clone();
+        async move {
+            let response = client
+                .get("http://localhost:3000/users")
+                .query(&[("cursor", cursor), ("page_size", "2".to_string())])
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status().as_u16(), 200);
+            response.json::<serde_json::Value>().await.unwrap()["d"].clone()
+        }
+    };
+    let ids = |page: &serde_json::Value| -> Vec<String> {
+        page["items"].as_array().unwrap().iter().map(|u| u["id"].as_str().unwrap().to_string()).collect()
+    };
+
+    let first = get_page(String::new()).await;
+    assert_eq!(ids(&first).len(), 2);
+    assert!(first["prev_cursor"].is_null());
+
+    let second = get_page(first["next_cursor"].as_str().unwrap().to_string()).await;
+    assert_eq!(ids(&second).len(), 2);
+    assert!(ids(&second).iter().all(|id| !ids(&first).contains(id)));
+
+    // walking back from the second page lands on the first one again
+    let back = get_page(second["prev_cursor"].as_str().unwrap().to_string()).await;
+    assert_eq!(ids(&back), ids(&first));
+
+    let response = client
+        .get("http://localhost:3000/users?cursor=not-a-cursor")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 400);
+}
+
+#[tokio::test]
+async fn test_get_users_count_modes() {
+    let client = reqwest::Client::new();
+    for _ in 0..3 {
+        client
+            .post("http://localhost:3000/users")
+            .bearer_auth(auth_token())
+            .json(&json!({
+                "username": generate_unique_username()
+            }))
+            .send()
+            .await
+            .unwrap();
+    }
+
+    let get = |query: &'static str| {
+        let client = client.clone();
+        async move {
+            let response = client
+                .get(format!("http://localhost:3000/users?page=1&page_size=2{}", query))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status().as_u16(), 200);
+            response.json::<serde_json::Value>().await.unwrap()["d"].clone()
+        }
+    };
+
+    let exact = get("").await;
+    assert_eq!(exact["items"].as_array().unwrap().len(), 2);
+    assert!(exact["total"].as_i64().unwrap() >= 3);
+    assert!(exact["has_next"].as_bool().unwrap());
+
+    let skipped = get("&count=none").await;
+    assert_eq!(skipped["items"].as_array().unwrap().len(), 2);
+    assert!(skipped["total_pages"].is_null());
+    assert!(skipped["total"].is_null());
+    assert!(skipped["has_next"].as_bool().unwrap());
+
+    // reltuples may not be populated yet, so only the shape is checked
+    let estimated = get("&count=estimated").await;
+    assert_eq!(estimated["items"].as_array().unwrap().len(), 2);
+    assert!(estimated["total"].is_null() || estimated["total"].is_i64());
+
+    // an empty first page counts 0, past the last row the total is unknown rather than 0
+    for (query, total) in [(format!("username_prefix={}", Uuid::new_v4()), json!(0)), ("page=100000000".to_string(), json!(null))] {
+        let body: serde_json::Value = client
+            .get(format!("http://localhost:3000/users?{}", query))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(body["d"]["items"].as_array().unwrap().len(), 0, "{}", query);
+        assert_eq!(body["d"]["total"], total, "{}", query);
+        assert_eq!(body["d"]["total_pages"], total, "{}", query);
+    }
+}
+
+#[tokio::test]
+async fn test_get_users_filter_and_sort() {
+    let client = reqwest::Client::new();
+    let prefix = generate_unique_username();
+    for suffix in ["b", "a", "c"] {
+        client
+            .post("http://localhost:3000/users")
+            .bearer_auth(auth_token())
+            .json(&json!({
+                "username": format!("{}_{}", prefix, suffix)
+            }))
+            .send()
+            .await
+            .unwrap();
+    }
+
+    let get = |query: String| {
+        let client = client.clone();
+        async move {
+            let response = client
+                .get(format!("http://localhost:3000/users?{}", query))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status().as_u16(), 200);
+            let body = response.json::<serde_json::Value>().await.unwrap();
+            body["d"]["items"].as_array().unwrap().iter()
+                .map(|u| u["username"].as_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        }
+    };
+    let names = |suffixes: &[&str]| suffixes.iter().map(|s| format!("{}_{}", prefix, s)).collect::<Vec<_>>();
+
+    assert_eq!(get(format!("username_prefix={}&sort=username", prefix)).await, names(&["a", "b", "c"]));
+    assert_eq!(get(format!("username_prefix={}&sort=-username", prefix)).await, names(&["c", "b", "a"]));
+    assert_eq!(get(format!("username_prefix={}", prefix)).await, names(&["c", "a", "b"]));
+    assert_eq!(get(format!("username={}_a&sort=-created_at,id", prefix)).await, names(&["a"]));
+    assert!(get(format!("username_prefix={}&created_after=2999-01-01T00:00:00Z", prefix)).await.is_empty());
+    assert_eq!(get(format!("username_prefix={}&created_before=2999-01-01T00:00:00Z", prefix)).await.len(), 3);
+
+    for query in ["sort=password_hash", "sort=username&cursor=", "created_after=yesterday", "usernam=alice", "page=1&bogus="] {
+        let response = client
+            .get(format!("http://localhost:3000/users?{}", query))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status().as_u16(), 400, "{}", query);
+    }
+}
+
+#[tokio::test]
+async fn test_get_users_by_meta() {
+    let client = reqwest::Client::new();
+    let username = generate_unique_username();
+    let tag = Uuid::new_v4().to_string();
+
+    let created: serde_json::Value = client
+        .post("http://localhost:3000/users")
+        .bearer_auth(auth_token())
+        .json(&json!({
+            "username": username
+        }))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    client
+        .patch(format!("http://localhost:3000/users/{}", created["id"].as_str().unwrap()))
+        .bearer_auth(auth_token())
+        .json(&json!({
+            "meta": {
+                "data": { "tag": tag },
+                "meta": { "first": { "value": tag }, "remark": { "value": "remark" } }
+            }
+        }))
+        .send()
+        .await
+        .unwrap();
+
+    let get = |query: String| {
+        let client = client.clone();
+        async move {
+            let response = client
+                .get(format!("http://localhost:3000/users?{}", query))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status().as_u16(), 200);
+            let body = response.json::<serde_json::Value>().await.unwrap();
+            body["d"]["items"].as_array().unwrap().iter()
+                .map(|u| u["username"].as_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        }
+    };
+
+    assert_eq!(get(format!("meta.data.tag={}", tag)).await, vec![username.clone()]);
+    assert_eq!(get(format!("meta.meta.first.value={}&meta.data.foo=1", tag)).await, vec![username.clone()]);
+    assert!(get(format!("meta.data.tag={}&meta.data.foo=2", tag)).await.is_empty());
+    // an empty value only checks that the key exists
+    assert_eq!(get(format!("username={}&meta.data.tag=", username)).await, vec![username.clone()]);
+    assert!(get(format!("username={}&meta.data.missing=", username)).await.is_empty());
+
+    for query in ["meta.password_hash=1", "meta.data..foo=1", "meta.meta.a.b.c.d=1"] {
+        let response = client
+            .get(format!("http://localhost:3000/users?{}", query))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status().as_u16(), 400, "{}", query);
+    }
+}
+
+#[tokio::test]
+async fn test_search_users() {
+    let client = reqwest::Client::new();
+    let word = Uuid::new_v4().simple().to_string()[..10].to_string();
+    let exact = word.clone();
+    let longer = format!("{}_operator", word);
+    for username in [&longer, &exact] {
+        client
+            .post("http://localhost:3000/users")
+            .bearer_auth(auth_token())
+            .json(&json!({
+                "username": username
+            }))
+            .send()
+            .await
+            .unwrap();
+    }
+
+    let search = |q: String| {
+        let client = client.clone();
+        async move {
+            let response = client
+                .get("http://localhost:3000/users/search")
+                .query(&[("q", q)])
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status().as_u16(), 200);
+            response.json::<serde_json::Value>().await.unwrap()["d"].clone()
+        }
+    };
+    let names = |page: &serde_json::Value| -> Vec<String> {
+        page["items"].as_array().unwrap().iter().map(|u| u["username"].as_str().unwrap().to_string()).collect()
+    };
+
+    // the closest name ranks first
+    let page = search(word.clone()).await;
+    assert_eq!(names(&page), vec![exact.clone(), longer.clone()]);
+    assert_eq!(page["total"], 2);
+
+    // partial and misspelled names still match
+    assert!(names(&search(word[2..9].to_string()).await).contains(&exact));
+    let typo = format!("{}x{}", &word[..5], &word[6..]);
+    assert_eq!(names(&search(typo).await).first(), Some(&exact));
+
+    for query in ["q=", "q=%20%20", "page_size=0&q=a"] {
+        let response = client
+            .get(format!("http://localhost:3000/users/search?{}", query))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status().as_u16(), 400, "{}", query);
+    }
+}
+
+#[tokio::test]
+async fn test_bulk_users() {
+    let client = reqwest::Client::new();
+    let (a, b, c) = (generate_unique_username(), generate_unique_username(), generate_unique_username());
+    let statuses = |body: &serde_json::Value| -> Vec<String> {
+        body["d"]["items"].as_array().unwrap().iter().map(|i| i["status"].as_str().unwrap().to_string()).collect()
+    };
+
+    // all-or-nothing (default): the duplicate rolls back the whole batch
+    let response = client
+        .post("http://localhost:3000/users/bulk")
+        .bearer_auth(auth_token())
+        .json(&json!([{ "username": a }, { "username": a }]))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 422);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert!(!body["d"]["committed"].as_bool().unwrap());
+    assert_eq!(statuses(&body), vec!["rolled_back", "conflict"]);
+
+    // best effort: valid items are committed, the others reported
+    let response = client
+        .post("http://localhost:3000/users/bulk?mode=best_effort")
+        .bearer_auth(auth_token())
+        .json(&json!([{ "username": a }, { "username": a }, {}, { "username": b, "password": "short" }, { "username": c }]))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert!(body["d"]["committed"].as_bool().unwrap());
+    assert_eq!(statuses(&body), vec!["created", "conflict", "invalid", "invalid", "created"]);
+    let id_a = body["d"]["items"][0]["id"].as_str().unwrap().to_string();
+    let id_c = body["d"]["items"][4]["id"].as_str().unwrap().to_string();
+
+    let response = client
+        .patch("http://localhost:3000/users/bulk?mode=best_effort")
+        .bearer_auth(auth_token())
+        .json(&json!([
+            { "id": id_a, "username": b },
+            { "id": id_c, "username": b },
+            { "id": "missing", "username": generate_unique_username() },
+        ]))
+        .send()
+        .await
+        .unwrap();
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(statuses(&body), vec!["updated", "conflict", "not_found"]);
+    let response = client.get(format!("http://localhost:3000/users/{}", id_a)).send().await.unwrap();
+    assert_eq!(response.json::<serde_json::Value>().await.unwrap()["d"]["username"], b);
+
+    let response = client
+        .delete("http://localhost:3000/users/bulk")
+        .bearer_auth(auth_token())
+        .json(&json!([id_a, "missing"]))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 422);
+    let response = client.get(format!("http://localhost:3000/users/{}", id_a)).send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+
+    let response = client
+        .delete("http://localhost:3000/users/bulk")
+        .bearer_auth(auth_token())
+        .json(&json!([id_a, id_c]))
+        .send()
+        .await
+        .unwrap();
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(statuses(&body), vec!["deleted", "deleted"]);
+    let response = client.get(format!("http://localhost:3000/users/{}", id_c)).send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 404);
+}
+
+#[tokio::test]
+async fn test_soft_delete_and_restore() {
+    let client = reqwest::Client::new();
+    let username = generate_unique_username();
+    let id = register_user(&client, &username).await;
+    let url = format!("http://localhost:3000/users/{}", id);
+
+    let response = client.delete(&url).bearer_auth(auth_token()).send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+
+    // gone from lookups, listings and login ...
+    assert_eq!(client.get(&url).send().await.unwrap().status().as_u16(), 404);
+    let body: serde_json::Value = client
+        .get(format!("http://localhost:3000/users?username={}", username))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert!(body["d"]["items"].as_array().unwrap().is_empty());
+    assert_eq!(login(&client, &username, TEST_PASSWORD).await.status().as_u16(), 401);
+    assert_eq!(client.delete(&url).bearer_auth(auth_token()).send().await.unwrap().status().as_u16(), 404);
+
+    // ... unless an admin asks for deleted users
+    let response = client.get(format!("{}?include_deleted=true", url)).send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 401);
+    let response = client
+        .get(format!("{}?include_deleted=true", url))
+        .bearer_auth(token_with_roles(&["viewer"]))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 403);
+    let body: serde_json::Value = client
+        .get(format!("http://localhost:3000/users?username={}&include_deleted=true", username))
+        .bearer_auth(auth_token())
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert!(body["d"]["items"][0]["deleted_at"].is_string());
+
+    let response = client
+        .post(format!("{}/restore", url))
+        .bearer_auth(auth_token())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(client.get(&url).send().await.unwrap().status().as_u16(), 200);
+    assert_eq!(login(&client, &username, TEST_PASSWORD).await.status().as_u16(), 200);
+
+    // only deleted users can be restored
+    let response = client
+        .post(format!("{}/restore", url))
+        .bearer_auth(auth_token())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 404);
+}
+
+#[tokio::test]
+async fn test_user_etag_and_if_match() {
+    let client = reqwest::Client::new();
+    let id = register_user(&client, &generate_unique_username()).await;
+    let url = format!("http://localhost:3000/users/{}", id);
+
+    let response = client.get(&url).send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(response.headers()["etag"], "\"1\"");
+
+    // unchanged since the last read
+    let response = client.get(&url).header("If-None-Match", "\"1\"").send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 304);
+    assert_eq!(response.headers()["etag"], "\"1\"");
+
+    let response = client
+        .patch(&url)
+        .bearer_auth(auth_token())
+        .header("If-Match", "\"1\"")
+        .json(&json!({"meta": {"data": {"foo": "etag-1"}}}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(response.headers()["etag"], "\"2\"");
+    let response = client.get(&url).header("If-None-Match", "\"1\"").send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+
+    // a second writer still holding version 1 loses
+    let response = client
+        .put(&url)
+        .bearer_auth(auth_token())
+        .header("If-Match", "\"1\"")
+        .json(&json!({"username": generate_unique_username()}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 412);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["r"], false);
+    assert!(body["e"].as_str().unwrap().starts_with("PreconditionFailedErr"));
+
+    let response = client
+        .delete(&url)
+        .bearer_auth(auth_token())
+        .header("If-Match", "\"1\"")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 412);
+
+    let response = client
+        .patch(&url)
+        .bearer_auth(auth_token())
+        .header("If-Match", "*")
+        .json(&json!({"meta": {"data": {"foo": "etag-2"}}}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(response.headers()["etag"], "\"3\"");
+
+    let response = client
+        .delete(&url)
+        .bearer_auth(auth_token())
+        .header("If-Match", "\"3\"")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+
+    // deleted users are not found rather than modified
+    let response = client
+        .patch(&url)
+        .bearer_auth(auth_token())
+        .header("If-Match", "\"4\"")
+        .json(&json!({"meta": {"data": {"foo": "etag-3"}}}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 404);
+}
+
+#[tokio::test]
+async fn test_user_history() {
+    let client = reqwest::Client::new();
+    let username = generate_unique_username();
+    let id = register_user(&client, &username).await;
+    let url = format!("http://localhost:3000/users/{}", id);
+
+    let response = client
+        .patch(&url)
+        .bearer_auth(auth_token())
+        .header("X-Request-Id", "history-patch")
+        .json(&json!({"meta": {"data": {"foo": "history"}}}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    // an oversized or malformed `X-Request-Id` is replaced by a generated one
+    let response = client
+        .delete(&url)
+        .bearer_auth(auth_token())
+        .header("x-request-id", format!("forged <script> {}", "x".repeat(500)))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    // a rejected change leaves no trace
+    let response = client.delete(&url).bearer_auth(auth_token()).send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 404);
+
+    let history_url = format!("{}/history", url);
+    let response = client.get(&history_url).send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 401);
+
+    let body: serde_json::Value = client
+        .get(&history_url)
+        .bearer_auth(token_with_roles(&["viewer"]))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(body["d"]["total"], 3);
+    let items = body["d"]["items"].as_array().unwrap();
+    let actions: Vec<&str> = items.iter().map(|item| item["action"].as_str().unwrap()).collect();
+    assert_eq!(actions, vec!["delete", "update", "create"]);
+
+    // `/register` is anonymous
+    let create = &items[2];
+    assert!(create["actor_id"].is_null());
+    assert!(create["before"].is_null());
+    assert_eq!(create["after"]["username"], username.as_str());
+    assert!(create["after"].get("password_hash").is_none());
+
+    let update = &items[1];
+    assert_eq!(update["actor_id"], "integration-test-admin");
+    assert_eq!(update["request_id"], "history-patch");
+    assert_eq!(update["before"]["meta"]["data"]["foo"], "1");
+    assert_eq!(update["after"]["meta"]["data"]["foo"], "history");
+
+    let delete = &items[0];
+    assert!(delete["before"]["deleted_at"].is_null());
+    assert!(delete["after"]["deleted_at"].is_string());
+    assert!(Uuid::parse_str(delete["request_id"].as_str().unwrap()).is_ok());
+
+    let body: serde_json::Value = client
+        .get(format!("{}?page=2&page_size=2", history_url))
+        .bearer_auth(auth_token())
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(body["d"]["items"].as_array().unwrap().len(), 1);
+    assert_eq!(body["d"]["items"][0]["action"], "create");
+    assert_eq!(body["d"]["has_next"], false);
+}
+
+// holds the user's row lock while a PATCH reads it, so the PATCH's snapshot is stale once the lock is released
+#[tokio::test]
+async fn test_patch_retries_serialization_failure() {
+    use diesel::{Connection, RunQueryDsl};
+
+    let client = reqwest::Client::new();
+    let id = register_user(&client, &generate_unique_username()).await;
+    let url = format!("http://localhost:3000/users/{}", id);
+
+    dotenv::dotenv().ok();
+    let mut conn = diesel::PgConnection::establish(&std::env::var("DATABASE_URL").unwrap()).unwrap();
+    diesel::sql_query("begin").execute(&mut conn).unwrap();
+    diesel::sql_query("update users set meta = jsonb_set(meta, '{data,concurrent}', '\"db\"') where id = $1")
+        .bind::<diesel::sql_types::Varchar, _>(&id)
+        .execute(&mut conn)
+        .unwrap();
+
+    let patch = tokio::spawn({
+        let client = client.clone();
+        let url = url.clone();
+        async move {
+            client
+                .patch(&url)
+                .bearer_auth(auth_token())
+                .json(&json!({"meta": {"data": {"patched": "http"}}}))
+                .send()
+                .await
+                .unwrap()
+        }
+    });
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    diesel::sql_query("commit").execute(&mut conn).unwrap();
+
+    let response = patch.await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(response.headers()["etag"], "\"3\"");
+    let body: serde_json::Value = response.json().await.unwrap();
+    // neither change is lost
+    assert_eq!(body["d"]["meta"]["data"]["concurrent"], "db");
+    assert_eq!(body["d"]["meta"]["data"]["patched"], "http");
+}
+
+#[test]
+fn test_config_bounds_acquire_timeout() {
+    // `Instant + timeout` overflows in r2d2 on every request
+    let (status, printed) = run_binary(&[("POOL_ACQUIRE_TIMEOUT_SECS", "18446744073709551615")], &[]);
+    assert_eq!(status.code(), Some(1));
+    assert!(printed.contains("POOL_ACQUIRE_TIMEOUT_SECS (environment): expected a whole number from 1 to 3600"), "{}", printed);
+
+    let (status, printed) = run_binary(&[("POOL_ACQUIRE_TIMEOUT_SECS", "3600")], &[]);
+    assert_eq!(status.code(), Some(1));
+    assert!(!printed.contains("POOL_ACQUIRE_TIMEOUT_SECS"), "{}", printed);
+}
+
+#[test]
+fn test_config_bounds_token_lifetimes() {
+    // `chrono::Duration::seconds` panics on the refresh token, `now + expires_in` overflows on the access token
+    let too_long = i64::MAX.to_string();
+    let (status, printed) = run_binary(&[("JWT_EXPIRES_SECS", &too_long), ("REFRESH_TOKEN_EXPIRES_SECS", &too_long)], &[]);
+    assert_eq!(status.code(), Some(1));
+    // every problem at once
+    assert!(printed.starts_with("invalid configuration:"), "{}", printed);
+    for expected in [
+        "DATABASE_URL: must be set",
+        "JWT_SECRET: must be set",
+        "JWT_EXPIRES_SECS (environment): expected a whole number from 1 to 31536000",
+        "REFRESH_TOKEN_EXPIRES_SECS (environment): expected a whole number from 1 to 31536000",
+    ] {
+        assert!(printed.contains(expected), "{} missing from {}", expected, printed);
+    }
+}
+
+#[test]
+fn test_config_precedence() {
+    let toml = ("config.toml", "[jwt]\nexpires_secs = \"from-toml\"\n[pool]\nsize = 0\n");
+    let dotenv = (".env", "JWT_EXPIRES_SECS=from-dotenv\n");
+
+    // the environment beats `.env`, which beats the file
+    let (_, printed) = run_binary(&[("JWT_EXPIRES_SECS", "from-env")], &[toml, dotenv]);
+    assert!(printed.contains("JWT_EXPIRES_SECS (environment): expected a whole number from 1 to 31536000, got `from-env`"), "{}", printed);
+    let (_, printed) = run_binary(&[], &[toml, dotenv]);
+    assert!(printed.contains("got `from-dotenv`"), "{}", printed);
+    let (_, printed) = run_binary(&[], &[toml]);
+    assert!(printed.contains("JWT_EXPIRES_SECS (config.toml): expected a whole number from 1 to 31536000, got `from-toml`"), "{}", printed);
+    assert!(printed.contains("POOL_SIZE (config.toml)"), "{}", printed);
+
+    // a valid value from a later layer replaces an invalid one, the defaults are valid
+    let (_, printed) = run_binary(&[("POOL_SIZE", "5")], &[toml]);
+    assert!(!printed.contains("POOL_SIZE"), "{}", printed);
+    let (_, printed) = run_binary(&[], &[]);
+    assert!(!printed.contains("POOL_SIZE") && !printed.contains("JWT_EXPIRES_SECS"), "{}", printed);
+}
+
+#[test]
+fn test_config_rejects_unknown_toml_keys() {
+    let (status, printed) = run_binary(&[("CONFIG_FILE", "settings.toml")], &[("settings.toml", "[pool]\nsise = 5\nsize = 5\n")]);
+    assert_eq!(status.code(), Some(1));
+    assert!(printed.contains("POOL_SISE (settings.toml): unknown setting"), "{}", printed);
+    assert!(!printed.contains("POOL_SIZE"), "{}", printed);
+}
+
+// a second server runs the purge as it starts, only the backdated user is past the default retention of 30 days
+#[tokio::test]
+async fn test_purge_records_audit_entries() {
+    use diesel::{Connection, QueryableByName, RunQueryDsl};
+    use diesel::sql_types::{Jsonb, Nullable, Varchar};
+
+    #[derive(QueryableByName)]
+    struct Entry {
+        #[diesel(sql_type = Nullable<Varchar>)]
+        actor_id: Option<String>,
+        #[diesel(sql_type = Varchar)]
+        request_id: String,
+        #[diesel(sql_type = Nullable<Jsonb>)]
+        before: Option<serde_json::Value>,
+        #[diesel(sql_type = Nullable<Jsonb>)]
+        after: Option<serde_json::Value>,
+    }
+
+    let client = reqwest::Client::new();
+    let id = register_user(&client, &generate_unique_username()).await;
+    let response = client
+        .delete(format!("http://localhost:3000/users/{}", id))
+        .bearer_auth(auth_token())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+
+    dotenv::dotenv().ok();
+    let database_url = std::env::var("DATABASE_URL").unwrap();
+    let jwt_secret = std::env::var("JWT_SECRET").unwrap();
+    let mut conn = diesel::PgConnection::establish(&database_url).unwrap();
+    diesel::sql_query("update users set deleted_at = now() - interval '1 year' where id = $1")
+        .bind::<Varchar, _>(&id)
+        .execute(&mut conn)
+        .unwrap();
+
+    let (mut command, dir) = binary(&[
+        ("DATABASE_URL", &database_url),
+        ("JWT_SECRET", &jwt_secret),
+        ("BIND_ADDR", "127.0.0.1:0"),
+        ("LOG_LEVEL", "warn"),
+    ], &[]);
+    let mut server = command.stdout(std::process::Stdio::null()).spawn().unwrap();
+
+    let mut entries = Vec::new();
+    for _ in 0..50 {
+        entries = diesel::sql_query("select actor_id, request_id, before, after from audit_log where user_id = $1 and action = 'purge'")
+            .bind::<Varchar, _>(&id)
+            .load::<Entry>(&mut conn)
+            .unwrap();
+        if !entries.is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+    server.kill().unwrap();
+    server.wait().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(entries.len(), 1);
+    let entry = &entries[0];
+    assert!(entry.actor_id.is_none());
+    assert!(entry.request_id.starts_with("purge-"));
+    assert_eq!(entry.before.as_ref().unwrap()["id"], id.as_str());
+    assert!(entry.before.as_ref().unwrap().get("password_hash").is_none());
+    assert!(entry.after.is_none());
+
+    let response = client
+        .get(format!("http://localhost:3000/users/{}?include_deleted=true", id))
+        .bearer_auth(auth_token())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 404);
+}
+
+// the schema check runs with or without `RUN_MIGRATIONS`, and before anything is migrated
+#[test]
+fn test_refuses_schema_ahead_of_binary() {
+    use diesel::{Connection, RunQueryDsl};
+
+    dotenv::dotenv().ok();
+    let database_url = std::env::var("DATABASE_URL").unwrap();
+    let jwt_secret = std::env::var("JWT_SECRET").unwrap();
+    // a schema of its own, so the shared one isn't touched
+    let schema = format!("ahead_{}", Uuid::new_v4().simple());
+    let mut conn = diesel::PgConnection::establish(&database_url).unwrap();
+    diesel::sql_query(format!("create schema {}", schema)).execute(&mut conn).unwrap();
+    diesel::sql_query(format!("create table {}.__diesel_schema_migrations (version varchar(50) primary key, run_on timestamp not null default now())", schema))
+        .execute(&mut conn)
+        .unwrap();
+    diesel::sql_query(format!("insert into {}.__diesel_schema_migrations (version) values ('29990101000000')", schema))
+        .execute(&mut conn)
+        .unwrap();
+
+    for run_migrations in ["false", "true"] {
+        let (status, printed) = run_binary(&[
+            ("DATABASE_URL", &database_url),
+            ("JWT_SECRET", &jwt_secret),
+            ("BIND_ADDR", "127.0.0.1:0"),
+            ("SESSION_SEARCH_PATH", &schema),
+            ("RUN_MIGRATIONS", run_migrations),
+        ], &[]);
+        assert_eq!(status.code(), Some(1), "{}", printed);
+        assert!(printed.contains("unknown migrations: 29990101000000"), "{}", printed);
+    }
+
+    let tables = diesel::sql_query(format!("select table_name from information_schema.tables where table_schema = '{}'", schema))
+        .execute(&mut conn)
+        .unwrap();
+    assert_eq!(tables, 1);
+    diesel::sql_query(format!("drop schema {} cascade", schema)).execute(&mut conn).unwrap();
+}
+
+// The database is behind a port nothing listens on until the test forwards it, so the second server starts degraded
+// and has to recover on its own.
+#[tokio::test]
+async fn test_degraded_mode() {
+    use tokio::net::{TcpListener, TcpStream};
+
+    let free_port = || std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let (db_port, server_port) = (free_port(), free_port());
+
+    dotenv::dotenv().ok();
+    let real_url = reqwest::Url::parse(&std::env::var("DATABASE_URL").unwrap()).unwrap();
+    let real_db = format!("{}:{}", real_url.host_str().unwrap(), real_url.port().unwrap_or(5432));
+    let mut database_url = real_url.clone();
+    database_url.set_host(Some("127.0.0.1")).unwrap();
+    database_url.set_port(Some(db_port)).unwrap();
+    let bind_addr = format!("127.0.0.1:{}", server_port);
+
+    let (mut command, dir) = binary(&[
+        ("DATABASE_URL", database_url.as_str()),
+        ("JWT_SECRET", &std::env::var("JWT_SECRET").unwrap()),
+        ("BIND_ADDR", &bind_addr),
+        ("STARTUP_DEGRADED", "true"),
+        ("STARTUP_DB_DEADLINE_SECS", "0"),
+        ("LOG_LEVEL", "warn"),
+    ], &[]);
+    let mut server = command.stdout(std::process::Stdio::null()).spawn().unwrap();
+
+    let client = reqwest::Client::new();
+    let get = |path: &str| client.get(format!("http://{}{}", bind_addr, path)).send();
+    let mut live = None;
+    for _ in 0..50 {
+        if let Ok(response) = get("/health/live").await {
+            live = Some(response.json::<serde_json::Value>().await.unwrap());
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert_eq!(live.unwrap()["d"]["status"], "degraded");
+
+    for path in ["/health/ready", "/users"] {
+        let response = get(path).await.unwrap();
+        assert_eq!(response.status().as_u16(), 503, "{}", path);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert!(!body["r"].as_bool().unwrap());
+        assert!(body["e"].as_str().unwrap().starts_with("DbUnavailableErr"), "{}", body);
+    }
+
+    // the database comes up
+    let listener = TcpListener::bind(("127.0.0.1", db_port)).await.unwrap();
+    let forwarding = tokio::spawn(async move {
+        loop {
+            let (mut inbound, _) = listener.accept().await.unwrap();
+            let real_db = real_db.clone();
+            tokio::spawn(async move {
+                let mut outbound = TcpStream::connect(real_db).await.unwrap();
+                let _ = tokio::io::copy_bidirectional(&mut inbound, &mut outbound).await;
+            });
+        }
+    });
+
+    // the retries back off up to 8s
+    let mut status = serde_json::Value::Null;
+    for _ in 0..100 {
+        status = get("/health/live").await.unwrap().json::<serde_json::Value>().await.unwrap()["d"]["status"].clone();
+        if status == "ok" {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+    assert_eq!(status, "ok");
+    assert_eq!(get("/users").await.unwrap().status().as_u16(), 200);
+
+    forwarding.abort();
+    server.kill().unwrap();
+    server.wait().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}